@@ -0,0 +1,410 @@
+use std::num::{NonZeroU32, NonZeroU64, NonZeroUsize};
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use clap::{ArgEnum, Args, Parser, Subcommand};
+use massa_auto_rebuy::config::Network;
+use massa_auto_rebuy::ids;
+use massa_models::{Address, Amount, BlockId, EndorsementId};
+
+/// Little executable to run aside of the node to automatically rebuy your rolls.
+/// Runs `rebuy` when no subcommand is given.
+#[derive(Debug, Parser)]
+#[clap(version)]
+pub struct Cli {
+    /// IP of the node, or the full URL of its public API (e.g. http://node.example.com:33035).
+    /// Several nodes separated by commas are failed over in order [env: MASSA_RPC_HOST]
+    pub host: Option<String>,
+    /// Public API port of the node [env: MASSA_RPC_PORT] [default: 33035]
+    pub port: Option<u16>,
+    /// Network the node runs on, selecting its default ports and expected roll price
+    #[clap(long, global = true, possible_values = ["testnet", "mainnet", "custom"])]
+    pub network: Option<Network>,
+    /// Private API port of the node, used to register staking keys [default: 33034]
+    #[clap(long, global = true)]
+    pub private_port: Option<u16>,
+    /// Read the settings from a TOML file, command line arguments override its values
+    #[clap(long, global = true)]
+    pub config: Option<PathBuf>,
+    /// Node `config.toml` whose [api] section gives the host and ports, after the explicit settings
+    #[clap(long, global = true)]
+    pub node_config: Option<PathBuf>,
+    /// Wallet file to use, repeat it to use the addresses of several wallets [default: wallet.dat]
+    #[clap(long, global = true, multiple_occurrences = true)]
+    pub wallet: Vec<PathBuf>,
+    /// Full URL of the node public API, the port argument is then ignored. Repeat it, or separate
+    /// URLs with commas, to fail over to the next node when one is unreachable
+    #[clap(long, global = true, conflicts_with = "host", multiple_occurrences = true, use_value_delimiter = true)]
+    pub url: Vec<String>,
+    /// Talk to the node over HTTPS, e.g. behind a TLS-terminating reverse proxy
+    #[clap(long, global = true)]
+    pub tls: bool,
+    /// Send the calls to the node through this proxy, e.g. socks5://127.0.0.1:1080 for an SSH tunnel
+    #[clap(long, global = true)]
+    pub proxy: Option<String>,
+    /// Connection attempts to the node, with an exponential backoff, before giving up [default: 5]
+    #[clap(long, global = true)]
+    pub connect_attempts: Option<NonZeroU32>,
+    /// Seconds a call to the node may take before failing [default: 30]
+    #[clap(long, global = true)]
+    pub rpc_timeout: Option<NonZeroU64>,
+    /// Log verbosity (error, warn, info, debug...), falls back to RUST_LOG then info
+    #[clap(long, global = true)]
+    pub log_level: Option<String>,
+    /// Only log warnings, errors and the sent roll buys, e.g. for cron jobs mailing any output
+    #[clap(long, short, global = true, conflicts_with = "log-level")]
+    pub quiet: bool,
+    /// Log the duration of each call to the node, also logged with --log-level debug
+    #[clap(long, global = true)]
+    pub time_rpc: bool,
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Buy rolls for every wallet address meeting the rebuy condition
+    Rebuy(RebuyArgs),
+    /// Sell rolls of the first wallet address
+    Sell(SellArgs),
+    /// Send coins from a wallet address to another address
+    Transfer(TransferArgs),
+    /// Print the status of the node
+    Status(StatusArgs),
+    /// Print the balances and rolls of the wallet addresses
+    Balance(BalanceArgs),
+    /// Print the active stakers of the current cycle and their rolls
+    Stakers(StakersArgs),
+    /// Print a block and its status
+    Block(BlockArgs),
+    /// Print the blocks of the graph within a time interval
+    Graph(GraphArgs),
+    /// Print the status of endorsements
+    Endorsements(EndorsementsArgs),
+    /// Sign a message with the node key (private API), e.g. to prove node ownership
+    Sign(SignArgs),
+    /// Ban peers from the node by IP (private API)
+    Ban(IpsArgs),
+    /// Lift the ban of peers by IP (private API)
+    Unban(IpsArgs),
+    /// Gracefully stop the node (private API)
+    StopNode(StopNodeArgs),
+    /// Stop staking with addresses (private API)
+    Unstake(UnstakeArgs),
+    /// Exit with 0 if the node answers and is synced, e.g. as a readiness probe
+    Probe(ProbeArgs),
+}
+
+#[derive(Debug, Default, Args)]
+pub struct RebuyArgs {
+    /// Re-run the check every INTERVAL seconds instead of exiting after one pass
+    #[clap(long)]
+    pub interval: Option<NonZeroU64>,
+    /// Run a single check and exit, the default without --interval
+    #[clap(long, conflicts_with = "interval")]
+    pub once: bool,
+    /// Only check these wallet addresses, comma separated [default: every wallet address]
+    #[clap(long, use_value_delimiter = true)]
+    pub addresses: Vec<Address>,
+    /// Shift each --interval wait by a random amount of up to JITTER seconds, earlier or later
+    #[clap(long, requires = "interval")]
+    pub jitter: Option<u64>,
+    /// Seed of the --jitter random generator, to reproduce a sequence of waits [default: current time]
+    #[clap(long, requires = "jitter")]
+    pub jitter_seed: Option<u64>,
+    /// Stop the --interval loop after this long, e.g. 90s, 30m, 6h or 1d (seconds without a unit)
+    #[clap(long, requires = "interval", parse(try_from_str = parse_duration))]
+    pub max_runtime: Option<Duration>,
+    /// Final balance (in MAS) required before buying [default: the node's roll price]
+    #[clap(long)]
+    pub min_balance: Option<Amount>,
+    /// Number of rolls to buy, capped to what the balance affords [default: 1]
+    #[clap(long)]
+    pub roll_count: Option<NonZeroU64>,
+    /// Buy as many rolls as the balance affords, keeping the reserve, instead of --roll-count
+    #[clap(long, conflicts_with = "roll-count")]
+    pub greedy: bool,
+    /// Reinvest the staking rewards: buy as many rolls as the final balance above --reserve affords,
+    /// even for addresses owning rolls, up to --max-rolls
+    #[clap(long, conflicts_with = "roll-count")]
+    pub compound: bool,
+    /// Buy the rolls missing for an address to own MIN_ROLLS candidate rolls, instead of only rebuying addresses left without rolls
+    #[clap(long)]
+    pub min_rolls: Option<NonZeroU64>,
+    /// Never buy past MAX_ROLLS candidate rolls for an address, skipping the addresses owning as many
+    #[clap(long)]
+    pub max_rolls: Option<NonZeroU64>,
+    /// Balance (in MAS) that must remain on the address after buying [default: 0]
+    #[clap(long)]
+    pub reserve: Option<Amount>,
+    /// Seconds an address is skipped after a buy, unless the node already reflects it [default: 300]
+    #[clap(long)]
+    pub cooldown: Option<u64>,
+    /// File the last buys are persisted to, so a restart does not buy twice [default: state.json]
+    #[clap(long)]
+    pub state_file: Option<PathBuf>,
+    /// After buying, register the address key with the node so it starts staking
+    #[clap(long)]
+    pub auto_stake: bool,
+    /// On each check, register again the keys of addresses owning rolls the node does not stake with
+    #[clap(long)]
+    pub ensure_staking: bool,
+    /// Defer the roll buys until the first quarter of a cycle
+    #[clap(long)]
+    pub buy_at_cycle_start: bool,
+    /// Only buy rolls for the addresses the node already stakes with
+    #[clap(long)]
+    pub only_staking: bool,
+    /// Most MAS spent on roll buys, fees included, over the last 24 hours (tracked in --state-file)
+    #[clap(long)]
+    pub max_daily_spend: Option<Amount>,
+    /// Times the roll buys are built again with a fresh expire period and resent after the node rejected them as expired [default: 2]
+    #[clap(long)]
+    pub expired_retries: Option<u32>,
+    /// Raise the fee of expired roll buys by PERCENT on each resubmission [default: 0]
+    #[clap(long)]
+    pub fee_bump: Option<u64>,
+    /// Checks the node configuration (thread count, t0, roll price...) is cached for before being fetched again [default: 60]
+    #[clap(long)]
+    pub refresh_config_every: Option<NonZeroU64>,
+    /// Number of addresses handled at the same time once their roll buys are sent (notification, staking registration, --wait) [default: 4]
+    #[clap(long)]
+    pub max_concurrency: Option<NonZeroUsize>,
+    /// Fail with exit code 4 when an address needs rolls but its balance does not cover one
+    #[clap(long)]
+    pub require_buy: bool,
+    /// Print one JSON line summarizing each check on stdout, the human-readable logs go to stderr.
+    /// Same as --output json
+    #[clap(long)]
+    pub json_log: bool,
+    /// How the result of each check is printed: `plain` prints the sent operation IDs, `table` the
+    /// decision taken for each address, `json` a JSON line like --json-log [default: plain]
+    #[clap(long, arg_enum, conflicts_with = "json-log")]
+    pub output: Option<OutputFormat>,
+    /// Load the wallet files again before each --interval check, to pick up the keys added meanwhile
+    #[clap(long, requires = "interval")]
+    pub refresh_wallet: bool,
+    /// When the --interval loop stops (Ctrl-C, SIGTERM or --max-runtime), print the checks, roll buys,
+    /// spend and errors of the session
+    #[clap(long, requires = "interval")]
+    pub summary_on_exit: bool,
+    /// Write the PID to this file while running, refusing to start if it names a running process
+    #[clap(long)]
+    pub pidfile: Option<PathBuf>,
+    /// Serve Prometheus metrics on HOST:PORT (requires the `metrics` feature)
+    #[clap(long)]
+    pub metrics_addr: Option<SocketAddr>,
+    /// Testing only: compare this balance (in MAS) to the threshold instead of the one of each
+    /// address. Implies --dry-run unless --send-simulated is given
+    #[clap(long, hide = true)]
+    pub simulate_balance: Option<Amount>,
+    /// Only print the decision taken for each address, reading nothing from the node but the
+    /// addresses and building no operation. The highest fee and the roll price of --network, else
+    /// --min-balance, are assumed [default output: table]
+    #[clap(long, conflicts_with = "send-simulated")]
+    pub check_only: bool,
+    /// Testing only: really send the operations decided with --simulate-balance
+    #[clap(long, hide = true, requires = "simulate-balance")]
+    pub send_simulated: bool,
+    #[clap(flatten)]
+    pub operation: OperationArgs,
+}
+
+impl RebuyArgs {
+    /// Format of the check results, `--json-log` standing for `--output json`.
+    pub fn output_format(&self) -> OutputFormat {
+        if self.json_log {
+            OutputFormat::Json
+        } else {
+            self.output
+                .unwrap_or(if self.check_only { OutputFormat::Table } else { OutputFormat::Plain })
+        }
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct SellArgs {
+    /// Number of rolls to sell
+    pub roll_count: NonZeroU64,
+    #[clap(flatten)]
+    pub operation: OperationArgs,
+}
+
+#[derive(Debug, Args)]
+pub struct TransferArgs {
+    /// Address receiving the coins
+    #[clap(long)]
+    pub to: Address,
+    /// Coins to send, as a MAS amount (e.g. "150.5")
+    #[clap(long)]
+    pub amount: Amount,
+    /// Wallet address sending the coins [default: the first wallet address]
+    #[clap(long)]
+    pub from: Option<Address>,
+    #[clap(flatten)]
+    pub operation: OperationArgs,
+}
+
+#[derive(Debug, Args)]
+pub struct StatusArgs {
+    /// Print the raw status as JSON
+    #[clap(long)]
+    pub json: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct BalanceArgs {
+    /// Print the balances as JSON
+    #[clap(long)]
+    pub json: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct StakersArgs {
+    /// Only print the wallet addresses
+    #[clap(long)]
+    pub mine: bool,
+    /// Print the stakers as JSON
+    #[clap(long)]
+    pub json: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct BlockArgs {
+    /// ID of the block
+    #[clap(parse(try_from_str = ids::parse_block_id))]
+    pub block_id: BlockId,
+    /// Print the raw block information as JSON
+    #[clap(long)]
+    pub json: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct EndorsementsArgs {
+    /// IDs of the endorsements
+    #[clap(required = true, parse(try_from_str = ids::parse_endorsement_id))]
+    pub endorsement_ids: Vec<EndorsementId>,
+    /// Print the raw endorsement information as JSON
+    #[clap(long)]
+    pub json: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct GraphArgs {
+    /// Start of the interval, included, in milliseconds since the unix epoch
+    #[clap(long)]
+    pub from: Option<u64>,
+    /// End of the interval, excluded, in milliseconds since the unix epoch
+    #[clap(long)]
+    pub to: Option<u64>,
+    /// Print the blocks as JSON
+    #[clap(long)]
+    pub json: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct SignArgs {
+    /// Message to sign
+    #[clap(required_unless_present = "sign-file")]
+    pub message: Option<String>,
+    /// Sign the raw bytes of this file instead
+    #[clap(long, conflicts_with = "message")]
+    pub sign_file: Option<PathBuf>,
+    /// Print the public key and signature as JSON
+    #[clap(long)]
+    pub json: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct IpsArgs {
+    /// IP addresses of the peers
+    #[clap(required = true)]
+    pub ips: Vec<IpAddr>,
+}
+
+#[derive(Debug, Args)]
+pub struct StopNodeArgs {
+    /// Stop the node without asking for confirmation
+    #[clap(long)]
+    pub yes: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct UnstakeArgs {
+    /// Addresses the node should stop staking with
+    #[clap(required = true)]
+    pub addresses: Vec<Address>,
+}
+
+#[derive(Debug, Args)]
+pub struct ProbeArgs {
+    /// Print the result of the probe, it only sets the exit code otherwise
+    #[clap(long)]
+    pub verbose: bool,
+}
+
+/// Options shared by the subcommands sending operations.
+#[derive(Debug, Default, Args)]
+pub struct OperationArgs {
+    /// Fee (in MAS) paid for each operation, a higher fee improves the odds of inclusion when the pool is busy [default: 0]
+    #[clap(long)]
+    pub fee: Option<Amount>,
+    /// How the fee is chosen: `fixed` pays --fee, `dynamic` scales with the node pool size up to --max-fee [default: fixed]
+    #[clap(long, arg_enum)]
+    pub fee_strategy: Option<FeeStrategyArg>,
+    /// Highest fee (in MAS) an operation may pay, the one the dynamic fee strategy reaches [default: 1]
+    #[clap(long)]
+    pub max_fee: Option<Amount>,
+    /// Pay fees above --max-fee instead of refusing to build the operation
+    #[clap(long)]
+    pub allow_high_fee: bool,
+    /// Build the operations and print what would be sent without broadcasting them
+    #[clap(long)]
+    pub dry_run: bool,
+    /// With --dry-run, print each signed operation that would be sent as a JSON line
+    #[clap(long, requires = "dry-run")]
+    pub json: bool,
+    /// After sending, poll the node until the operations are final
+    #[clap(long)]
+    pub wait: bool,
+    /// Seconds --wait polls before giving up [default: 300]
+    #[clap(long)]
+    pub wait_timeout: Option<u64>,
+    /// Extra attempts at sending an operation after a connection error, rejections are never retried [default: 3]
+    #[clap(long)]
+    pub send_retries: Option<u32>,
+    /// Compute the current slot with the local clock as is, instead of aligning it on the node's time
+    #[clap(long)]
+    pub no_clock_compensation: bool,
+    /// POST a JSON notification to this webhook after each operation and on fatal errors
+    #[clap(long)]
+    pub notify_url: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ArgEnum)]
+pub enum OutputFormat {
+    Plain,
+    Table,
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, ArgEnum)]
+pub enum FeeStrategyArg {
+    Fixed,
+    Dynamic,
+}
+
+/// Parse a duration made of a number and an optional unit: `s` (default), `m`, `h` or `d`.
+fn parse_duration(value: &str) -> Result<Duration, String> {
+    let (number, unit) = value.split_at(value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len()));
+    let number: u64 = number.parse().map_err(|_| format!("invalid duration '{}'", value))?;
+    let seconds = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(format!("invalid duration unit '{}', expected s, m, h or d", unit)),
+    };
+    Ok(Duration::from_secs(number.saturating_mul(seconds)))
+}
@@ -0,0 +1,174 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use massa_models::Amount;
+use serde::Deserialize;
+
+use crate::error::{RebuyError, Result};
+
+/// Content of the optional `--config` TOML file.
+/// Every field can be overridden by the matching command line argument.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Network whose preset fills the defaults, see `NETWORK_PRESETS`
+    pub network: Option<Network>,
+    /// IP of the node
+    pub host: Option<String>,
+    /// Public API port of the node
+    pub port: Option<u16>,
+    /// Balance required before buying, parsed as a MAS amount (e.g. "150.5")
+    pub min_balance: Option<String>,
+    /// Number of rolls bought each time the check fires
+    pub roll_count: Option<u64>,
+    /// Seconds between two checks, runs a single pass when absent
+    pub interval: Option<u64>,
+    /// Path of the wallet file
+    pub wallet_path: Option<PathBuf>,
+    /// Fee paid for each operation, parsed as a MAS amount
+    pub fee: Option<String>,
+    /// Settings of specific addresses, `[[address]]` tables
+    #[serde(default, rename = "address")]
+    pub addresses: Vec<AddressConfig>,
+}
+
+/// `[[address]]` table of the configuration file.
+/// Each field replaces the global setting (including the command line one) for this address.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AddressConfig {
+    /// Address these settings apply to
+    pub address: String,
+    /// Balance required before buying, parsed as a MAS amount
+    pub min_balance: Option<String>,
+    /// Number of rolls bought each time the check fires
+    pub roll_count: Option<u64>,
+    /// Candidate rolls the address never goes past
+    pub max_rolls: Option<u64>,
+    /// Fixed fee paid for the roll buys of the address, parsed as a MAS amount
+    pub fee: Option<String>,
+}
+
+impl Config {
+    /// Read and deserialize the configuration file at `path`.
+    pub fn load(path: &Path) -> Result<Config> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| RebuyError::Config(format!("unable to read config file {}: {}", path.display(), e)))?;
+        toml::from_str(&content)
+            .map_err(|e| RebuyError::Config(format!("invalid config file {}: {}", path.display(), e)))
+    }
+}
+
+/// `[api]` section of the node's own `config.toml`, the rest of the file is ignored.
+#[derive(Debug, Deserialize)]
+pub struct NodeApiConfig {
+    /// Address the public API listens on
+    pub bind_public: SocketAddr,
+    /// Address the private API listens on
+    pub bind_private: SocketAddr,
+}
+
+#[derive(Deserialize)]
+struct NodeConfigFile {
+    api: NodeApiConfig,
+}
+
+impl NodeApiConfig {
+    /// Read the `[api]` section of the node configuration file at `path`.
+    pub fn load(path: &Path) -> Result<NodeApiConfig> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| RebuyError::Config(format!("unable to read node config file {}: {}", path.display(), e)))?;
+        toml::from_str::<NodeConfigFile>(&content)
+            .map(|file| file.api)
+            .map_err(|e| RebuyError::Config(format!("invalid [api] section in node config file {}: {}", path.display(), e)))
+    }
+
+    /// Host to reach the node at: the public API bind address, or the loopback when it listens on
+    /// every interface.
+    pub fn host(&self) -> IpAddr {
+        match self.bind_public.ip() {
+            IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+            IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+            ip => ip,
+        }
+    }
+}
+
+/// Massa network the node runs on, selecting the defaults of `NETWORK_PRESETS`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Network {
+    Testnet,
+    Mainnet,
+    /// Any other network, e.g. a local one: only the generic defaults apply
+    Custom,
+}
+
+/// Defaults selected by `--network`, each one overridden by its explicit setting.
+#[derive(Debug)]
+pub struct NetworkPreset {
+    pub network: Network,
+    /// Public API port of the node
+    pub port: u16,
+    /// Private API port of the node
+    pub private_port: u16,
+    /// Roll price the node is expected to report, in MAS. A different one means the node runs
+    /// on another network than intended.
+    pub roll_price: Option<&'static str>,
+}
+
+pub static NETWORK_PRESETS: [NetworkPreset; 3] = [
+    NetworkPreset {
+        network: Network::Testnet,
+        port: 33035,
+        private_port: 33034,
+        roll_price: Some("100"),
+    },
+    NetworkPreset {
+        network: Network::Mainnet,
+        port: 33035,
+        private_port: 33034,
+        roll_price: Some("100"),
+    },
+    NetworkPreset {
+        network: Network::Custom,
+        port: 33035,
+        private_port: 33034,
+        roll_price: None,
+    },
+];
+
+impl Network {
+    /// Entry of `NETWORK_PRESETS` for this network.
+    pub fn preset(self) -> &'static NetworkPreset {
+        NETWORK_PRESETS
+            .iter()
+            .find(|preset| preset.network == self)
+            .expect("every network has a preset")
+    }
+}
+
+impl NetworkPreset {
+    /// Roll price the node is expected to report on this network, if known.
+    pub fn roll_price(&self) -> Option<Amount> {
+        self.roll_price
+            .map(|price| Amount::from_str(price).expect("preset roll prices are valid amounts"))
+    }
+}
+
+impl FromStr for Network {
+    type Err = RebuyError;
+
+    fn from_str(s: &str) -> Result<Network> {
+        match s {
+            "testnet" => Ok(Network::Testnet),
+            "mainnet" => Ok(Network::Mainnet),
+            "custom" => Ok(Network::Custom),
+            _ => Err(RebuyError::Config(format!(
+                "unknown network '{}', expected testnet, mainnet or custom",
+                s
+            ))),
+        }
+    }
+}
@@ -0,0 +1,98 @@
+//! Failure modes of the rebuy logic, so a caller can tell them apart.
+
+use jsonrpc_core_client::RpcError;
+use massa_models::{Address, Amount};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum RebuyError {
+    /// The node could not be reached or did not answer in time
+    #[error("check if your node is running: {0}")]
+    Connection(String),
+    /// The node answered the call with an error, e.g. rejecting an invalid operation
+    #[error("node rejected the request: {0}")]
+    OperationRejected(String),
+    /// The wallet file could not be loaded or holds no key
+    #[error("{0}")]
+    WalletLoad(String),
+    /// The wallet has no public key for the address sending an operation
+    #[error("missing public key for {0}")]
+    MissingPublicKey(Address),
+    /// The wallet has no private key for an address to stake with
+    #[error("missing private key for {0}")]
+    MissingPrivateKey(Address),
+    /// The address cannot pay for the requested operation
+    #[error("insufficient balance on {address}: {balance} available, {required} required")]
+    InsufficientBalance {
+        address: Address,
+        balance: Amount,
+        required: Amount,
+    },
+    /// The address owns fewer rolls than requested to sell
+    #[error("cannot sell {requested} roll(s): {address} only owns {owned}")]
+    InsufficientRolls {
+        address: Address,
+        owned: u64,
+        requested: u64,
+    },
+    /// Invalid command line arguments, environment variables or configuration file
+    #[error("{0}")]
+    Config(String),
+    /// The operation could not be built
+    #[error("unable to build the operation: {0}")]
+    Operation(String),
+    /// A built operation failed the local checks run before sending it
+    #[error("invalid operation: {0}")]
+    InvalidOperation(String),
+    /// The current slot could not be computed from the node configuration
+    #[error("unable to compute the current slot: {0}")]
+    Slot(String),
+    /// The sent operations were not final before the `--wait` timeout
+    #[error("{pending} operation(s) not final after {timeout}s")]
+    NotFinal { pending: usize, timeout: u64 },
+    /// The node does not list the address among its staking addresses after registering its key
+    #[error("{0} is not staking after registering its key")]
+    NotStaking(Address),
+    /// The node still lists these addresses among its staking addresses after removing them
+    #[error("{} address(es) still staking after removal", .0.len())]
+    StillStaking(Vec<Address>),
+    /// Some roll buys of a check failed, each one is logged
+    #[error("{} of {total} roll buy(s) failed", errors.len())]
+    BuysFailed { errors: Vec<RebuyError>, total: usize },
+    /// The latest final block of the node lags behind the current slot
+    #[error("the node is not synced yet: last final slot {last_slot}, current slot {current_slot}")]
+    NotSynced { last_slot: String, current_slot: String },
+    /// The state file could not be read or written
+    #[error("{0}")]
+    State(String),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+}
+
+impl RebuyError {
+    /// Process exit code reporting the error: 2 when the node cannot be reached, 3 when it rejected
+    /// an operation, 4 for an insufficient balance, 5 when it is not synced, 1 otherwise.
+    /// Failed roll buys report the code of the first failure.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            RebuyError::Connection(_) => 2,
+            RebuyError::OperationRejected(_) => 3,
+            RebuyError::InsufficientBalance { .. } => 4,
+            RebuyError::NotSynced { .. } => 5,
+            RebuyError::BuysFailed { errors, .. } => errors.first().map_or(1, RebuyError::exit_code),
+            _ => 1,
+        }
+    }
+}
+
+/// Calls answered by the node are rejections, every other failure comes from the connection.
+impl From<RpcError> for RebuyError {
+    fn from(e: RpcError) -> Self {
+        match e {
+            RpcError::JsonRpcError(e) => RebuyError::OperationRejected(e.message),
+            e => RebuyError::Connection(e.to_string()),
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, RebuyError>;
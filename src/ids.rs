@@ -0,0 +1,99 @@
+//! Parsing of the IDs given on the command line, with errors telling what was expected.
+
+use std::fmt::Display;
+use std::str::FromStr;
+
+use massa_models::{BlockId, EndorsementId, OperationId};
+
+use crate::error::{RebuyError, Result};
+
+/// Parse an operation ID, e.g. one printed after a roll buy.
+pub fn parse_operation_id(value: &str) -> Result<OperationId> {
+    parse_id(value, "operation")
+}
+
+/// Parse an endorsement ID.
+pub fn parse_endorsement_id(value: &str) -> Result<EndorsementId> {
+    parse_id(value, "endorsement")
+}
+
+/// Parse a block ID.
+pub fn parse_block_id(value: &str) -> Result<BlockId> {
+    parse_id(value, "block")
+}
+
+/// Parse the ID of a `kind` of object, all of them being base58check encoded hashes.
+fn parse_id<T>(value: &str, kind: &str) -> Result<T>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    let trimmed = value.trim();
+    if trimmed.is_empty() {
+        return Err(RebuyError::Config(format!("empty {} ID", kind)));
+    }
+    if let Some(c) = trimmed.chars().find(|c| !c.is_ascii_alphanumeric() || matches!(c, '0' | 'O' | 'I' | 'l')) {
+        return Err(RebuyError::Config(format!(
+            "invalid {} ID '{}': '{}' is not a base58 character",
+            kind, trimmed, c
+        )));
+    }
+    T::from_str(trimmed).map_err(|e| {
+        RebuyError::Config(format!(
+            "invalid {} ID '{}': not a base58check encoded {} hash ({})",
+            kind, trimmed, kind, e
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// base58check encoding of a 32 bytes hash
+    const VALID_ID: &str = "stVppBFPTfo5Eu9LRGQktavngb3JqgeiJtGr7h8cnkgndqMAg";
+
+    #[test]
+    fn parses_valid_ids() {
+        assert_eq!(parse_operation_id(VALID_ID).unwrap().to_string(), VALID_ID);
+        assert_eq!(parse_endorsement_id(VALID_ID).unwrap().to_string(), VALID_ID);
+        assert_eq!(parse_block_id(VALID_ID).unwrap().to_string(), VALID_ID);
+    }
+
+    #[test]
+    fn trims_surrounding_whitespace() {
+        let padded = format!("  {}\n", VALID_ID);
+        assert_eq!(parse_operation_id(&padded).unwrap().to_string(), VALID_ID);
+    }
+
+    #[test]
+    fn rejects_empty_id() {
+        let error = parse_block_id("  ").unwrap_err().to_string();
+        assert_eq!(error, "empty block ID");
+    }
+
+    #[test]
+    fn rejects_non_base58_character() {
+        let error = parse_operation_id("stVppBFPTfo5Eu9LRGQkt0vngb3JqgeiJtGr7h8cnkgndqMAg")
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("'0' is not a base58 character"), "{}", error);
+        let error = parse_operation_id("stVppBFPTfo5-Eu9LRGQk").unwrap_err().to_string();
+        assert!(error.contains("'-' is not a base58 character"), "{}", error);
+    }
+
+    #[test]
+    fn rejects_wrong_checksum() {
+        let error = parse_endorsement_id("stVppBFPTfo5Eu9LRGQktavngb3JqgeiJtGr7h8cnkgndqMAh")
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("not a base58check encoded endorsement hash"), "{}", error);
+    }
+
+    #[test]
+    fn rejects_hash_of_wrong_length() {
+        // valid base58check, but of 16 bytes
+        let error = parse_block_id("2cNFgFUukWxugrrpcSfh79NanNTo").unwrap_err().to_string();
+        assert!(error.contains("not a base58check encoded block hash"), "{}", error);
+    }
+}
@@ -0,0 +1,17 @@
+//! Automatically rebuy the rolls of a massa wallet, see `RebuyClient`.
+//! The `massa-auto-rebuy` binary is a command line wrapper around this crate.
+
+pub mod config;
+pub mod error;
+pub mod ids;
+pub mod notify;
+#[macro_use]
+pub mod rpc;
+pub mod rebuy;
+pub mod state;
+pub mod telemetry;
+pub mod wallet;
+
+pub use error::{RebuyError, Result};
+pub use wallet::Wallets;
+pub use rebuy::{AddressOverrides, AddressReport, BuyFailure, BuyReport, Decision, RebuyClient, RebuyReport, Settings, Underfunded};
@@ -1,28 +1,938 @@
-mod rpc;
+mod cli;
+mod pidfile;
 
-use std::{path::PathBuf, str::FromStr};
+use std::num::{NonZeroU64, NonZeroUsize};
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::Arc;
+use std::{path::PathBuf, str::FromStr, time::Duration};
 
-use massa_wallet::Wallet;
-use anyhow::{Result};
+use anyhow::{anyhow, bail, Context};
+use clap::Parser;
+use massa_auto_rebuy::config::{Config, Network, NodeApiConfig};
+use massa_auto_rebuy::notify::Notifier;
+use massa_auto_rebuy::rebuy::{self, AddressOverrides, RebuyClient, Settings};
+use massa_auto_rebuy::rpc::FeeStrategy;
+use massa_auto_rebuy::{rpc, state, telemetry, RebuyError, RebuyReport, Result, Wallets};
+use massa_models::api::TimeInterval;
+use massa_models::{Address, Amount, BlockId, EndorsementId};
+use serde::Serialize;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use tokio::sync::Notify;
+use tokio::time::Instant;
+use tracing::{error, info, warn};
+use tracing_subscriber::EnvFilter;
+
+use crate::cli::{Cli, Command, FeeStrategyArg, OperationArgs, OutputFormat, RebuyArgs};
+use crate::pidfile::Pidfile;
+
+/// Public API port of the node when none is given.
+const DEFAULT_PORT: u16 = 33035;
+
+/// Private API port of the node when none is given.
+const DEFAULT_PRIVATE_PORT: u16 = 33034;
+
+/// Wallet loaded when none is given.
+const DEFAULT_WALLET_PATH: &str = "wallet.dat";
+
+/// File the last buys are persisted to when none is given.
+const DEFAULT_STATE_FILE: &str = "state.json";
+
+/// Fee paid for each operation, in MAS.
+/// A higher fee makes the operation more attractive to block producers when the pool is busy.
+const DEFAULT_FEE: &str = "0";
+
+/// Highest fee an operation may pay without `--allow-high-fee`, in MAS.
+const DEFAULT_MAX_FEE: &str = "1";
+
+/// Log filter used when neither `--log-level` nor `RUST_LOG` is set.
+const DEFAULT_LOG_LEVEL: &str = "info";
+
+/// Log filter of `--quiet`, the sent roll buys being added back.
+const QUIET_LOG_LEVEL: &str = "warn";
+
+/// Seconds `--wait` waits for the operations to be final.
+const DEFAULT_WAIT_TIMEOUT: u64 = 300;
+
+/// Number of addresses handled at the same time once their operations are sent.
+const DEFAULT_MAX_CONCURRENCY: usize = 4;
+
+/// Extra attempts at sending an operation after a connection error.
+const DEFAULT_SEND_RETRIES: u32 = 3;
+
+/// Seconds an address is skipped after a buy while the node does not reflect it.
+const DEFAULT_COOLDOWN: u64 = 300;
+
+/// Resubmissions of roll buys rejected by the node as expired.
+const DEFAULT_EXPIRED_RETRIES: u32 = 2;
+
+/// Checks the node configuration is cached for before being fetched again.
+const DEFAULT_REFRESH_CONFIG_EVERY: u64 = 60;
+
+/// Seconds a call to the node may take before failing.
+const DEFAULT_RPC_TIMEOUT: u64 = 30;
+
+/// Periods of blocks `status` looks through for the last final slot of each thread.
+const STATUS_FINAL_PERIODS: u64 = 10;
+
+/// Seconds a call to the node may take in `probe` when `--rpc-timeout` is not given.
+const PROBE_RPC_TIMEOUT: u64 = 5;
+
+/// Number of connection attempts made before giving up on the node.
+const DEFAULT_CONNECT_ATTEMPTS: u32 = 5;
+
+/// Environment variable read for the host when it is not given on the command line.
+const ENV_RPC_HOST: &str = "MASSA_RPC_HOST";
+
+/// Environment variable read for the public API port when it is not given on the command line.
+const ENV_RPC_PORT: &str = "MASSA_RPC_PORT";
+
+/// Merge the command line arguments with the configuration file, the former taking precedence.
+fn rebuy_settings(
+    rebuy: &RebuyArgs,
+    operation: &OperationArgs,
+    config: &Config,
+    network: Option<Network>,
+) -> Result<Settings> {
+    let roll_count = match rebuy.roll_count {
+        Some(roll_count) => roll_count.get(),
+        None => config.roll_count.unwrap_or(1),
+    };
+    if roll_count == 0 {
+        return Err(RebuyError::Config("roll_count must be greater than zero".to_string()));
+    }
+    if let (Some(min_rolls), Some(max_rolls)) = (rebuy.min_rolls, rebuy.max_rolls) {
+        if min_rolls > max_rolls {
+            return Err(RebuyError::Config(format!(
+                "--min-rolls {} is above --max-rolls {}",
+                min_rolls, max_rolls
+            )));
+        }
+    }
+    let min_balance = match (rebuy.min_balance, &config.min_balance) {
+        (Some(min_balance), _) => Some(min_balance),
+        (None, Some(min_balance)) => Some(parse_amount(min_balance, "min_balance")?),
+        (None, None) => None,
+    };
+    let fee = match (operation.fee, &config.fee) {
+        (Some(fee), _) => fee,
+        (None, Some(fee)) => parse_amount(fee, "fee")?,
+        (None, None) => parse_amount(DEFAULT_FEE, "fee")?,
+    };
+    let max_fee = match operation.max_fee {
+        Some(max_fee) => max_fee,
+        None => parse_amount(DEFAULT_MAX_FEE, "max_fee")?,
+    };
+    let fee = match operation.fee_strategy {
+        None | Some(FeeStrategyArg::Fixed) => FeeStrategy::Fixed(fee),
+        Some(FeeStrategyArg::Dynamic) => FeeStrategy::Dynamic { max: max_fee },
+    };
+    let mut overrides = HashMap::new();
+    for entry in &config.addresses {
+        let address = Address::from_str(&entry.address)
+            .map_err(|e| RebuyError::Config(format!("invalid [[address]] '{}': {}", entry.address, e)))?;
+        if entry.roll_count == Some(0) {
+            return Err(RebuyError::Config(format!("roll_count of {} must be greater than zero", address)));
+        }
+        let address_overrides = AddressOverrides {
+            min_balance: entry
+                .min_balance
+                .as_deref()
+                .map(|min_balance| parse_amount(min_balance, "min_balance"))
+                .transpose()?,
+            roll_count: entry.roll_count,
+            max_rolls: entry.max_rolls,
+            fee: entry.fee.as_deref().map(|fee| parse_amount(fee, "fee")).transpose()?,
+        };
+        if overrides.insert(address, address_overrides).is_some() {
+            return Err(RebuyError::Config(format!("several [[address]] entries for {}", address)));
+        }
+    }
+    // a mistyped fee is refused at startup, rather than when a buy is due
+    if !operation.allow_high_fee {
+        check_max_fee("fee", fee.max(), max_fee)?;
+        for (address, address_overrides) in &overrides {
+            if let Some(fee) = address_overrides.fee {
+                check_max_fee(&format!("fee of the [[address]] {}", address), fee, max_fee)?;
+            }
+        }
+    }
+    let settings = Settings {
+        min_balance,
+        roll_count,
+        addresses: rebuy.addresses.clone(),
+        greedy: rebuy.greedy,
+        compound: rebuy.compound,
+        min_rolls: rebuy.min_rolls.map(NonZeroU64::get),
+        max_rolls: rebuy.max_rolls.map(NonZeroU64::get),
+        reserve: rebuy.reserve.unwrap_or_else(|| Amount::from_raw(0)),
+        fee,
+        max_fee: (!operation.allow_high_fee).then_some(max_fee),
+        // a simulated balance never sends real operations unless explicitly acknowledged
+        dry_run: operation.dry_run || (rebuy.simulate_balance.is_some() && !rebuy.send_simulated),
+        dry_run_json: operation.json,
+        check_only: rebuy.check_only,
+        wait: operation.wait.then(|| {
+            Duration::from_secs(operation.wait_timeout.unwrap_or(DEFAULT_WAIT_TIMEOUT))
+        }),
+        notifier: operation.notify_url.clone().map(Notifier::new),
+        max_concurrency: rebuy
+            .max_concurrency
+            .map_or(DEFAULT_MAX_CONCURRENCY, NonZeroUsize::get),
+        send_retries: operation.send_retries.unwrap_or(DEFAULT_SEND_RETRIES),
+        clock_compensation: !operation.no_clock_compensation,
+        cooldown: Duration::from_secs(rebuy.cooldown.unwrap_or(DEFAULT_COOLDOWN)),
+        state_file: rebuy
+            .state_file
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_STATE_FILE)),
+        auto_stake: rebuy.auto_stake,
+        ensure_staking: rebuy.ensure_staking,
+        buy_at_cycle_start: rebuy.buy_at_cycle_start,
+        only_staking: rebuy.only_staking,
+        simulated_balance: rebuy.simulate_balance,
+        max_daily_spend: rebuy.max_daily_spend,
+        expired_retries: rebuy.expired_retries.unwrap_or(DEFAULT_EXPIRED_RETRIES),
+        fee_bump: rebuy.fee_bump.unwrap_or(0),
+        overrides,
+        refresh_config_every: rebuy
+            .refresh_config_every
+            .map_or(DEFAULT_REFRESH_CONFIG_EVERY, NonZeroU64::get),
+        expected_roll_price: network.and_then(|network| network.preset().roll_price()),
+    };
+    if settings.check_only {
+        settings.check_only_roll_price()?;
+    }
+    Ok(settings)
+}
+
+/// Refuse a configured fee, described by `name`, above `max_fee`.
+fn check_max_fee(name: &str, fee: Amount, max_fee: Amount) -> Result<()> {
+    if fee > max_fee {
+        return Err(RebuyError::Config(format!(
+            "{} {} is above the maximum fee {}, pass --allow-high-fee to pay it",
+            name, fee, max_fee
+        )));
+    }
+    Ok(())
+}
 
-#[paw::main]
 #[tokio::main]
-async fn main(args: paw::Args) -> Result<()> {
-    let mut args = args.skip(1);
+async fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+
+    // --log-level and --quiet take precedence over RUST_LOG
+    let filter = match &cli.log_level {
+        Some(level) => EnvFilter::try_new(level)
+            .map_err(|e| anyhow!("invalid --log-level '{}': {}", level, e))?,
+        None if cli.quiet => {
+            EnvFilter::new(QUIET_LOG_LEVEL).add_directive(format!("{}=info", rebuy::BUY_TARGET).parse()?)
+        }
+        None => EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(DEFAULT_LOG_LEVEL)),
+    };
+    let filter = if cli.time_rpc {
+        filter.add_directive(format!("{}=debug", rpc::RPC_TIMING_TARGET).parse()?)
+    } else {
+        filter
+    };
+    // keep stdout for the output of the commands, e.g. JSON piped to another tool
+    tracing_subscriber::fmt().with_env_filter(filter).with_writer(std::io::stderr).init();
+
+    // command line arguments take precedence over the configuration file
+    let config = match &cli.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    let command = cli
+        .command
+        .unwrap_or_else(|| Command::Rebuy(RebuyArgs::default()));
+    let network = cli.network.or(config.network);
+    let settings = match &command {
+        Command::Rebuy(args) => Some(rebuy_settings(args, &args.operation, &config, network)?),
+        Command::Sell(args) => Some(rebuy_settings(&RebuyArgs::default(), &args.operation, &config, network)?),
+        Command::Transfer(args) => Some(rebuy_settings(&RebuyArgs::default(), &args.operation, &config, network)?),
+        Command::Status(_)
+        | Command::Balance(_)
+        | Command::Stakers(_)
+        | Command::Block(_)
+        | Command::Graph(_)
+        | Command::Endorsements(_)
+        | Command::Sign(_)
+        | Command::Ban(_)
+        | Command::Unban(_)
+        | Command::StopNode(_)
+        | Command::Unstake(_)
+        | Command::Probe(_) => None,
+    };
+
+    // command line arguments, then environment variables, then the configuration file
+    let env_port = match std::env::var(ENV_RPC_PORT) {
+        Ok(port) => Some(
+            port.parse::<u16>()
+                .map_err(|e| anyhow!("invalid {} '{}': {}", ENV_RPC_PORT, port, e))?,
+        ),
+        Err(_) => None,
+    };
+    // then the node's own configuration file
+    let node_api = cli.node_config.as_deref().map(NodeApiConfig::load).transpose()?;
+    let hosts: Vec<String> = if cli.url.is_empty() {
+        let host = cli
+            .host
+            .or_else(|| std::env::var(ENV_RPC_HOST).ok())
+            .or(config.host)
+            .or_else(|| node_api.as_ref().map(|node_api| node_api.host().to_string()))
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::Other, "the host argument is missing"))?;
+        host.split(',').map(|host| host.trim().to_string()).collect()
+    } else {
+        cli.url
+    };
+    // then the --network preset
+    let preset = network.map(Network::preset);
+    let port = cli
+        .port
+        .or(env_port)
+        .or(config.port)
+        .or(node_api.as_ref().map(|node_api| node_api.bind_public.port()))
+        .or(preset.map(|preset| preset.port))
+        .unwrap_or(DEFAULT_PORT);
+    let private_port = cli
+        .private_port
+        .or(node_api.as_ref().map(|node_api| node_api.bind_private.port()))
+        .or(preset.map(|preset| preset.private_port))
+        .unwrap_or(DEFAULT_PRIVATE_PORT);
+    let wallet_paths = if cli.wallet.is_empty() {
+        vec![config.wallet_path.unwrap_or_else(|| PathBuf::from(DEFAULT_WALLET_PATH))]
+    } else {
+        cli.wallet
+    };
+    // a probe must answer quickly, without retrying
+    let probe = matches!(&command, Command::Probe(_));
+    let connect_attempts = match cli.connect_attempts {
+        Some(connect_attempts) => connect_attempts.get(),
+        None if probe => 1,
+        None => DEFAULT_CONNECT_ATTEMPTS,
+    };
+    let tls = cli.tls;
+    let rpc_timeout = match cli.rpc_timeout {
+        Some(rpc_timeout) => rpc_timeout.get(),
+        None if probe => PROBE_RPC_TIMEOUT,
+        None => DEFAULT_RPC_TIMEOUT,
+    };
+    let rpc_timeout = Duration::from_secs(rpc_timeout);
+    let quiet_probe = matches!(&command, Command::Probe(args) if !args.verbose);
+    let interval = match &command {
+        // --once on the command line overrides an interval of the configuration file
+        Command::Rebuy(args) if args.once => None,
+        Command::Rebuy(args) => match args.interval.map(NonZeroU64::get).or(config.interval) {
+            Some(0) => bail!("interval must be greater than zero"),
+            Some(interval) => Some(Duration::from_secs(interval)),
+            None => {
+                info!("running a single check, pass --interval to keep checking or --once to hide this hint");
+                None
+            }
+        },
+        _ => None,
+    };
+
+    let notifier = settings.as_ref().and_then(|settings| settings.notifier.clone());
+    let result: anyhow::Result<()> = async {
+        // removed when the command ends, taken before any call to the node so a second instance
+        // fails at once rather than after waiting for the node
+        let _pidfile = match &command {
+            Command::Rebuy(args) => args.pidfile.as_deref().map(Pidfile::create).transpose()?,
+            _ => None,
+        };
+        // loaded before contacting the node, so a mistyped --addresses fails at once
+        let rebuy_wallets = match (&command, &settings) {
+            (Command::Rebuy(_), Some(settings)) => {
+                let wallets = Wallets::load(&wallet_paths)?;
+                wallets.check_addresses(&settings.addresses)?;
+                Some(wallets)
+            }
+            _ => None,
+        };
+        let client = rpc::Client::new(&hosts, port, private_port, tls, cli.proxy.as_deref(), connect_attempts, rpc_timeout).await?;
+        // the private API is only reached by the commands using it, a check-only pass only by
+        // fetching the addresses
+        if !matches!(&command, Command::Rebuy(args) if args.check_only) {
+            client.public.wait_ready().await?;
+        }
+        match (command, settings) {
+            (Command::Rebuy(args), Some(settings)) => {
+                let wallets = rebuy_wallets.expect("the wallets of rebuy are loaded before connecting");
+                if let Some(addr) = args.metrics_addr {
+                    telemetry::install(addr)?;
+                    info!(%addr, "serving metrics");
+                }
+                let check_only = settings.check_only;
+                let rebuy_client = RebuyClient::new(client, wallets, settings);
+                if !check_only {
+                    rebuy_client.check_node_config().await?;
+                }
+                rebuy_client.load_state().await?;
+                match interval {
+                    None => run_check(&rebuy_client, &args, None).await,
+                    Some(period) => run_daemon(&rebuy_client, period, &args).await,
+                }
+            }
+            (Command::Sell(args), Some(settings)) => {
+                let wallets = Wallets::load(&wallet_paths)?;
+                let rebuy_client = RebuyClient::new(client, wallets, settings);
+                rebuy_client.check_node_config().await?;
+                let operation_ids = rebuy_client.sell_rolls(args.roll_count.get()).await?;
+                rpc::print_operation_ids(&operation_ids, true)?;
+                Ok(())
+            }
+            (Command::Transfer(args), Some(settings)) => {
+                let wallets = Wallets::load(&wallet_paths)?;
+                let rebuy_client = RebuyClient::new(client, wallets, settings);
+                rebuy_client.check_node_config().await?;
+                let operation_ids = rebuy_client.transfer(args.from, args.to, args.amount).await?;
+                rpc::print_operation_ids(&operation_ids, true)?;
+                Ok(())
+            }
+            (Command::Status(args), _) => Ok(print_status(&client, args.json).await?),
+            (Command::Balance(args), _) => {
+                let wallets = Wallets::load(&wallet_paths)?;
+                Ok(print_balances(&client, &wallets, args.json).await?)
+            }
+            (Command::Stakers(args), _) => {
+                let wallets = if args.mine {
+                    Some(Wallets::load(&wallet_paths)?)
+                } else {
+                    None
+                };
+                Ok(print_stakers(&client, wallets.as_ref(), args.json).await?)
+            }
+            (Command::Block(args), _) => Ok(print_block(&client, args.block_id, args.json).await?),
+            (Command::Graph(args), _) => Ok(print_graph(&client, args.from, args.to, args.json).await?),
+            (Command::Endorsements(args), _) => {
+                Ok(print_endorsements(&client, args.endorsement_ids, args.json).await?)
+            }
+            (Command::Sign(args), _) => {
+                let message = match (args.message, args.sign_file) {
+                    (_, Some(path)) => std::fs::read(&path)
+                        .with_context(|| format!("unable to read {}", path.display()))?,
+                    (Some(message), None) => message.into_bytes(),
+                    (None, None) => unreachable!("clap requires a message or --sign-file"),
+                };
+                Ok(sign_message(&client, message, args.json).await?)
+            }
+            (Command::Ban(args), _) => {
+                client.private.ban(args.ips.clone()).await.map_err(RebuyError::from)?;
+                for ip in args.ips {
+                    println!("Banned {}", ip);
+                }
+                Ok(())
+            }
+            (Command::Unban(args), _) => {
+                client.private.unban(args.ips.clone()).await.map_err(RebuyError::from)?;
+                for ip in args.ips {
+                    println!("Unbanned {}", ip);
+                }
+                Ok(())
+            }
+            (Command::StopNode(args), _) => {
+                if !args.yes && !confirm(&format!("Stop the node at {}?", client.private.active_url()))? {
+                    bail!("stop-node aborted");
+                }
+                client.private.stop_node().await.map_err(RebuyError::from)?;
+                println!("Node stopped");
+                Ok(())
+            }
+            (Command::Unstake(args), _) => Ok(unstake(&client, args.addresses).await?),
+            (Command::Probe(args), _) => {
+                let status = client.public.get_status().await.map_err(RebuyError::from)?;
+                // the probe has no --no-clock-compensation, the current slot is the node's
+                let node_config = rpc::NodeConfig::from_status(&status, true)?;
+                rpc::check_synced(&client.public, &node_config).await?;
+                if args.verbose {
+                    println!("Node {} is up and synced", client.public.active_url());
+                }
+                Ok(())
+            }
+            _ => unreachable!("settings are built for every subcommand sending operations"),
+        }
+    }
+    .await;
 
-    let ip = args
-        .next()
-        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::Other, "the host argument is missing"))?;
-    let port = args
-        .next()
-        .unwrap_or("33035".to_string()).parse().unwrap();
-    let client = rpc::Client::new(ip.parse().unwrap(), port).await;
-    let wallet = Wallet::new(PathBuf::from("wallet.dat"))?;
-    let wallet_info =  client.0.get_addresses(wallet.get_full_wallet().keys().copied().collect()).await;
-    if let Ok(wallet_addresses) = wallet_info {
-        if !wallet_addresses.is_empty() && wallet_addresses[0].rolls.candidate_rolls == 0 &&  wallet_addresses[0].ledger_info.final_ledger_info.balance >= massa_models::Amount::from_str("1").unwrap() {
-            rpc::send_operation(&client, &wallet, massa_models::OperationType::RollBuy{ roll_count: 1 }, massa_models::Amount::from_raw(0), wallet_addresses[0].address, true).await?;
+    if let Err(e) = result {
+        if let Some(notifier) = &notifier {
+            notifier.error(format!("{:#}", e)).await;
         }
+        let exit_code = e.downcast_ref::<RebuyError>().map_or(1, RebuyError::exit_code);
+        if !quiet_probe {
+            eprintln!("Error: {:?}", e);
+        }
+        std::process::exit(exit_code);
+    }
+    Ok(())
+}
+
+/// Print the main fields of the node status, or all of it as JSON.
+async fn print_status(client: &rpc::Client, json: bool) -> Result<()> {
+    let status = client.public.get_status().await.map_err(RebuyError::from)?;
+    // the slots of the status come from the node's clock, the final ones from its block graph
+    let node_config = rpc::NodeConfig::from_status(&status, true)?;
+    let last_final_slots = rpc::last_final_slots(&client.public, &node_config, STATUS_FINAL_PERIODS).await?;
+    if json {
+        let mut status = serde_json::to_value(&status)?;
+        status["last_final_slots"] = serde_json::to_value(&last_final_slots)?;
+        println!("{}", serde_json::to_string_pretty(&status)?);
+        return Ok(());
     }
+    println!("Node id: {}", status.node_id);
+    println!("Version: {}", status.version);
+    println!("Network time: {}", status.current_time);
+    println!("Current cycle: {}", status.current_cycle);
+    println!("Connected nodes: {}", status.connected_nodes.len());
+    println!("Last final slots:");
+    for (thread, slot) in last_final_slots.iter().enumerate() {
+        match slot {
+            Some(slot) => println!("  thread {}: {}", thread, slot),
+            None => println!("  thread {}: none in the last {} periods", thread, STATUS_FINAL_PERIODS),
+        }
+    }
+    println!("Next slot: {}", status.next_slot);
+    Ok(())
+}
+
+/// Balances and rolls of a wallet address, as printed by the `balance` subcommand.
+#[derive(Serialize)]
+struct AddressBalance {
+    address: String,
+    final_balance: String,
+    candidate_balance: String,
+    active_rolls: u64,
+    candidate_rolls: u64,
+}
+
+/// Print the balances and rolls of every wallet address as a table, or as JSON.
+async fn print_balances(client: &rpc::Client, wallets: &Wallets, json: bool) -> Result<()> {
+    let wallet_addresses = rebuy::fetch_wallet_addresses(client, wallets, &[]).await?;
+    let balances: Vec<AddressBalance> = wallet_addresses
+        .iter()
+        .map(|address_info| AddressBalance {
+            address: address_info.address.to_string(),
+            final_balance: address_info.ledger_info.final_ledger_info.balance.to_string(),
+            candidate_balance: address_info.ledger_info.candidate_ledger_info.balance.to_string(),
+            active_rolls: address_info.rolls.active_rolls,
+            candidate_rolls: address_info.rolls.candidate_rolls,
+        })
+        .collect();
+    if json {
+        println!("{}", serde_json::to_string_pretty(&balances)?);
+        return Ok(());
+    }
+    println!(
+        "{:<52} {:>20} {:>20} {:>12} {:>15}",
+        "Address", "Final balance", "Candidate balance", "Active rolls", "Candidate rolls"
+    );
+    for balance in balances {
+        println!(
+            "{:<52} {:>20} {:>20} {:>12} {:>15}",
+            balance.address,
+            balance.final_balance,
+            balance.candidate_balance,
+            balance.active_rolls,
+            balance.candidate_rolls
+        );
+    }
+    Ok(())
+}
+
+/// Active staker of the current cycle, as printed by the `stakers` subcommand.
+#[derive(Serialize)]
+struct Staker {
+    address: String,
+    rolls: u64,
+}
+
+/// Print the active stakers and their rolls, the largest first, as a table or as JSON.
+/// With `wallets`, only their addresses are printed.
+async fn print_stakers(client: &rpc::Client, wallets: Option<&Wallets>, json: bool) -> Result<()> {
+    let stakers = client.public.get_stakers().await.map_err(RebuyError::from)?;
+    let mut stakers: Vec<Staker> = stakers
+        .into_iter()
+        .filter(|(address, _)| wallets.is_none_or(|wallets| wallets.contains(address)))
+        .map(|(address, rolls)| Staker {
+            address: address.to_string(),
+            rolls,
+        })
+        .collect();
+    stakers.sort_by(|a, b| b.rolls.cmp(&a.rolls).then_with(|| a.address.cmp(&b.address)));
+    if json {
+        println!("{}", serde_json::to_string_pretty(&stakers)?);
+        return Ok(());
+    }
+    println!("{:<52} {:>12}", "Address", "Rolls");
+    for staker in stakers {
+        println!("{:<52} {:>12}", staker.address, staker.rolls);
+    }
+    Ok(())
+}
+
+/// Print the status, slot, creator and operation count of a block, or all of it as JSON.
+async fn print_block(client: &rpc::Client, block_id: BlockId, json: bool) -> Result<()> {
+    let block = client.public.get_block(block_id).await.map_err(RebuyError::from)?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&block)?);
+        return Ok(());
+    }
+    println!("Block: {}", block.id);
+    let content = match block.content {
+        Some(content) => content,
+        None => {
+            println!("Unknown to the node");
+            return Ok(());
+        }
+    };
+    println!("Final: {}", content.is_final);
+    println!("Stale: {}", content.is_stale);
+    println!("In blockclique: {}", content.is_in_blockclique);
+    println!("Slot: {}", content.block.header.content.slot);
+    println!("Creator: {}", content.block.header.content.creator);
+    println!("Operations: {}", content.block.operations.len());
     Ok(())
 }
+
+/// Print whether each endorsement is final, pending in the pool or included in blocks, or all
+/// of their information as JSON.
+async fn print_endorsements(client: &rpc::Client, endorsement_ids: Vec<EndorsementId>, json: bool) -> Result<()> {
+    let endorsements = client
+        .public
+        .get_endorsements(endorsement_ids)
+        .await
+        .map_err(RebuyError::from)?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&endorsements)?);
+        return Ok(());
+    }
+    for (index, endorsement) in endorsements.iter().enumerate() {
+        if index > 0 {
+            println!();
+        }
+        let content = &endorsement.endorsement.content;
+        println!("Endorsement: {}", endorsement.id);
+        println!("Final: {}", endorsement.is_final);
+        println!("In pool: {}", endorsement.in_pool);
+        println!("Slot: {}", content.slot);
+        println!("Index: {}", content.index);
+        println!("Endorsed block: {}", content.endorsed_block);
+        let in_blocks: Vec<String> = endorsement.in_blocks.iter().map(ToString::to_string).collect();
+        println!("In blocks: {}", if in_blocks.is_empty() { "none".to_string() } else { in_blocks.join(", ") });
+    }
+    Ok(())
+}
+
+/// Print the blocks created between `from` (included) and `to` (excluded), in milliseconds since
+/// the unix epoch, as a table or as JSON.
+async fn print_graph(client: &rpc::Client, from: Option<u64>, to: Option<u64>, json: bool) -> Result<()> {
+    let interval = TimeInterval {
+        start: from.map(Into::into),
+        end: to.map(Into::into),
+    };
+    let mut blocks = client.public.get_graph_interval(interval).await.map_err(RebuyError::from)?;
+    blocks.sort_by_key(|block| block.slot);
+    if json {
+        println!("{}", serde_json::to_string_pretty(&blocks)?);
+        return Ok(());
+    }
+    println!("{:<52} {:>12} {:>6} {:>6} {:>11}", "Block", "Slot", "Final", "Stale", "Blockclique");
+    for block in blocks {
+        println!(
+            "{:<52} {:>12} {:>6} {:>6} {:>11}",
+            block.id,
+            block.slot.to_string(),
+            block.is_final,
+            block.is_stale,
+            block.is_in_blockclique
+        );
+    }
+    Ok(())
+}
+
+/// Ask `question` on the terminal, only a `y` or `yes` answer confirms.
+fn confirm(question: &str) -> std::io::Result<bool> {
+    print!("{} [y/N] ", question);
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Have the node sign `message` with its key, then print its public key and the signature.
+async fn sign_message(client: &rpc::Client, message: Vec<u8>, json: bool) -> Result<()> {
+    let signed = client.private.node_sign_message(message).await.map_err(RebuyError::from)?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&signed)?);
+        return Ok(());
+    }
+    println!("Public key: {}", signed.public_key);
+    println!("Signature: {}", signed.signature);
+    Ok(())
+}
+
+/// Have the node stop staking with `addresses`, then check with its staking addresses which ones
+/// were actually removed.
+async fn unstake(client: &rpc::Client, addresses: Vec<Address>) -> Result<()> {
+    let before = client.private.get_staking_addresses().await.map_err(RebuyError::from)?;
+    client
+        .private
+        .remove_staking_addresses(addresses.clone())
+        .await
+        .map_err(RebuyError::from)?;
+    let after = client.private.get_staking_addresses().await.map_err(RebuyError::from)?;
+    let mut remaining = Vec::new();
+    for address in addresses {
+        if after.contains(&address) {
+            warn!(%address, "still staking after removal");
+            remaining.push(address);
+        } else if before.contains(&address) {
+            println!("Removed {}", address);
+        } else {
+            println!("{} was not staking", address);
+        }
+    }
+    if !remaining.is_empty() {
+        return Err(RebuyError::StillStaking(remaining));
+    }
+    Ok(())
+}
+
+/// Run the rebuy check every `period`, shifted by up to `--jitter`, until Ctrl-C or SIGTERM is received.
+/// A SIGHUP runs an extra check right away, leaving the schedule unchanged.
+/// A check in progress is always completed before exiting.
+async fn run_daemon(rebuy: &RebuyClient, period: Duration, args: &RebuyArgs) -> Result<()> {
+    let (shutdown_tx, mut shutdown_rx) = tokio::sync::watch::channel(false);
+    tokio::spawn(async move {
+        if shutdown_signal().await {
+            info!("shutting down after current cycle");
+            let _ = shutdown_tx.send(true);
+        }
+    });
+    let trigger = Arc::new(Notify::new());
+    #[cfg(unix)]
+    match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+        Ok(mut hangup) => {
+            let trigger = trigger.clone();
+            tokio::spawn(async move {
+                while hangup.recv().await.is_some() {
+                    trigger.notify_one();
+                }
+            });
+        }
+        Err(e) => warn!("unable to listen to SIGHUP, manual checks are disabled: {}", e),
+    }
+
+    let jitter = Duration::from_secs(args.jitter.unwrap_or(0));
+    let mut rng = StdRng::seed_from_u64(args.jitter_seed.unwrap_or_else(state::now));
+    // the first check runs at startup, the next ones `period` after the previous one ended,
+    // without bursts to catch up after a slow check
+    let started = Instant::now();
+    let mut next_check = started;
+    let deadline = args.max_runtime.map(|max_runtime| started + max_runtime);
+    let mut summary = SessionSummary::default();
+    loop {
+        let manual = tokio::select! {
+            biased;
+            Ok(()) = shutdown_rx.changed() => None,
+            _ = tokio::time::sleep_until(deadline.unwrap_or(next_check)), if deadline.is_some() => {
+                info!("--max-runtime reached, shutting down");
+                None
+            }
+            _ = tokio::time::sleep_until(next_check) => Some(false),
+            _ = trigger.notified() => Some(true),
+        };
+        let manual = match manual {
+            Some(manual) => manual,
+            None => break,
+        };
+        if manual {
+            info!("SIGHUP received, running a manual check");
+        }
+        if args.refresh_wallet {
+            if let Err(e) = rebuy.reload_wallets().await {
+                warn!("unable to reload the wallets, keeping the loaded ones: {}", e);
+            }
+        }
+        if let Err(e) = run_check(rebuy, args, Some(&mut summary)).await {
+            error!("rebuy check failed, retrying next tick: {}", e);
+        }
+        if !manual {
+            next_check = Instant::now() + jittered(period, jitter, &mut rng);
+        }
+    }
+    if args.summary_on_exit {
+        summary.uptime_secs = started.elapsed().as_secs();
+        summary.print(args.output_format());
+    }
+    Ok(())
+}
+
+/// Totals of a daemon session, printed by `--summary-on-exit`.
+#[derive(Default, Serialize)]
+struct SessionSummary {
+    checks: u64,
+    /// Roll buys sent, not counting dry runs
+    buys: u64,
+    rolls: u64,
+    /// Price of the rolls and fees of the sent roll buys
+    spent: u64,
+    /// Failed checks and roll buys
+    errors: u64,
+    uptime_secs: u64,
+}
+
+impl SessionSummary {
+    /// Add the result of a check to the totals.
+    fn record(&mut self, result: &Result<RebuyReport>) {
+        self.checks += 1;
+        let report = match result {
+            Ok(report) => report,
+            Err(_) => {
+                self.errors += 1;
+                return;
+            }
+        };
+        for buy in report.buys.iter().filter(|buy| !buy.operation_ids.is_empty()) {
+            self.buys += 1;
+            self.rolls += buy.roll_count;
+            self.spent = self.spent.saturating_add(buy.cost.to_raw());
+        }
+        self.errors += report.failures.len() as u64;
+    }
+
+    /// Print the totals as a line of text, or of JSON with `--output json`.
+    fn print(&self, output: OutputFormat) {
+        if output == OutputFormat::Json {
+            println!("{}", serde_json::json!({ "summary": self }));
+            return;
+        }
+        println!(
+            "Session summary: {} checks, {} roll buys ({} rolls), {} spent, {} errors, up {}s",
+            self.checks,
+            self.buys,
+            self.rolls,
+            Amount::from_raw(self.spent),
+            self.errors,
+            self.uptime_secs
+        );
+    }
+}
+
+/// Wait for Ctrl-C (SIGINT) or, on unix, SIGTERM as sent by `systemctl stop`.
+/// Returns false if no signal can be listened to.
+async fn shutdown_signal() -> bool {
+    #[cfg(unix)]
+    {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut terminate) => tokio::select! {
+                result = tokio::signal::ctrl_c() => result.is_ok(),
+                _ = terminate.recv() => true,
+            },
+            Err(e) => {
+                warn!("unable to listen to SIGTERM, only Ctrl-C stops the loop cleanly: {}", e);
+                tokio::signal::ctrl_c().await.is_ok()
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        tokio::signal::ctrl_c().await.is_ok()
+    }
+}
+
+/// `period` shifted by a random offset between `-jitter` and `+jitter`.
+fn jittered(period: Duration, jitter: Duration, rng: &mut StdRng) -> Duration {
+    if jitter.is_zero() {
+        return period;
+    }
+    let offset = Duration::from_millis(rng.gen_range(0..=jitter.as_millis() as u64));
+    if rng.gen::<bool>() {
+        period + offset
+    } else {
+        period.saturating_sub(offset)
+    }
+}
+
+/// Parse a MAS amount read from the configuration file, e.g. `150.5`.
+fn parse_amount(value: &str, field: &str) -> Result<Amount> {
+    Amount::from_str(value).map_err(|e| RebuyError::Config(format!("invalid {} '{}': {}", field, value, e)))
+}
+
+/// Print the balance, rolls and decision of each address checked, with the IDs of the sent operations.
+fn print_report_table(report: &RebuyReport) {
+    println!(
+        "{:<52} {:>20} {:>15} {:<14} {:>10}  Operations",
+        "Address", "Final balance", "Candidate rolls", "Decision", "Rolls"
+    );
+    for decision in &report.decisions {
+        let operation_ids: Vec<String> = decision.operation_ids.iter().map(ToString::to_string).collect();
+        println!(
+            "{:<52} {:>20} {:>15} {:<14} {:>10}  {}",
+            decision.address,
+            decision.balance.to_string(),
+            decision.candidate_rolls,
+            decision.decision.to_string(),
+            decision.roll_count,
+            operation_ids.join(",")
+        );
+    }
+}
+
+/// `--output json` line of a check that failed before buying anything.
+#[derive(Serialize)]
+struct CheckError {
+    /// Milliseconds since the unix epoch when the check started
+    timestamp: u64,
+    error: String,
+}
+
+/// Run a rebuy check and print its result in the `--output` format: the sent operation IDs, a
+/// table of the decision taken for each address, or a single JSON line summarizing the check.
+/// Fails if any roll buy failed, or with `--require-buy` if an address needing rolls could not
+/// afford one. The result is added to the `summary` of a daemon session.
+async fn run_check(rebuy: &RebuyClient, args: &RebuyArgs, summary: Option<&mut SessionSummary>) -> Result<()> {
+    let output = args.output_format();
+    let timestamp = state::now();
+    let result = rebuy.check_and_rebuy().await;
+    if let Some(summary) = summary {
+        summary.record(&result);
+    }
+    if output == OutputFormat::Json {
+        let line = match &result {
+            Ok(report) => serde_json::to_string(report)?,
+            Err(e) => serde_json::to_string(&CheckError {
+                timestamp,
+                error: format!("{:#}", e),
+            })?,
+        };
+        println!("{}", line);
+    }
+    let report = result?;
+    match output {
+        OutputFormat::Plain => {
+            let operation_ids: Vec<_> = report.buys.iter().flat_map(|buy| buy.operation_ids.iter().copied()).collect();
+            rpc::print_operation_ids(&operation_ids, false)?;
+        }
+        OutputFormat::Table => print_report_table(&report),
+        OutputFormat::Json => {}
+    }
+    if !report.failures.is_empty() {
+        let total = report.failures.len() + report.buys.len();
+        return Err(RebuyError::BuysFailed {
+            errors: report.failures.into_iter().map(|failure| failure.error).collect(),
+            total,
+        });
+    }
+    match report.underfunded.into_iter().next() {
+        Some(underfunded) if args.require_buy => Err(RebuyError::InsufficientBalance {
+            address: underfunded.address,
+            balance: underfunded.balance,
+            required: underfunded.required,
+        }),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,85 @@
+//! Webhook notifications about roll buys and fatal errors.
+
+use massa_models::{Address, Amount, OperationId};
+use serde::Serialize;
+use tracing::warn;
+
+use crate::state::now;
+
+/// JSON body posted to the webhook.
+#[derive(Debug, Serialize)]
+struct Payload {
+    /// `info` for a roll buy, `error` for a fatal error
+    level: &'static str,
+    address: Option<String>,
+    roll_count: Option<u64>,
+    fee: Option<String>,
+    operation_ids: Vec<String>,
+    message: Option<String>,
+    /// Milliseconds since the unix epoch
+    timestamp: u64,
+}
+
+/// Posts notifications to the `--notify-url` webhook.
+/// Failures are logged and never abort the rebuy.
+#[derive(Clone)]
+pub struct Notifier {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl Notifier {
+    pub fn new(url: String) -> Notifier {
+        Notifier {
+            client: reqwest::Client::new(),
+            url,
+        }
+    }
+
+    /// Notify a successful roll buy.
+    pub async fn roll_buy(
+        &self,
+        address: Address,
+        roll_count: u64,
+        fee: Amount,
+        operation_ids: &[OperationId],
+    ) {
+        self.post(Payload {
+            level: "info",
+            address: Some(address.to_string()),
+            roll_count: Some(roll_count),
+            fee: Some(fee.to_string()),
+            operation_ids: operation_ids.iter().map(|id| id.to_string()).collect(),
+            message: None,
+            timestamp: now(),
+        })
+        .await
+    }
+
+    /// Notify an error that stopped the tool.
+    pub async fn error(&self, message: String) {
+        self.post(Payload {
+            level: "error",
+            address: None,
+            roll_count: None,
+            fee: None,
+            operation_ids: Vec::new(),
+            message: Some(message),
+            timestamp: now(),
+        })
+        .await
+    }
+
+    async fn post(&self, payload: Payload) {
+        let result = self
+            .client
+            .post(&self.url)
+            .json(&payload)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        if let Err(e) = result {
+            warn!("unable to send notification to {}: {}", self.url, e);
+        }
+    }
+}
@@ -0,0 +1,85 @@
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use massa_auto_rebuy::{RebuyError, Result};
+use tracing::warn;
+
+/// `--pidfile` holding the PID of the running process, removed when dropped.
+pub struct Pidfile {
+    path: PathBuf,
+}
+
+impl Pidfile {
+    /// Write the PID of the process to `path`, the file being created only if it does not exist yet.
+    /// An existing file is replaced if it names a process known to be dead, refused otherwise.
+    /// Whether a process runs is only known through `/proc`, elsewhere any existing file is refused.
+    pub fn create(path: &Path) -> Result<Pidfile> {
+        let mut file = match create_new(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                // an empty file may be the one of an instance starting right now
+                let content = std::fs::read_to_string(path).unwrap_or_default();
+                let pid = match content.trim().parse::<u32>() {
+                    Ok(pid) => pid,
+                    Err(_) => {
+                        return Err(RebuyError::Config(format!(
+                            "pidfile {} exists without a PID, remove it if no other instance is running",
+                            path.display()
+                        )))
+                    }
+                };
+                match is_running(pid) {
+                    Some(true) => {
+                        return Err(RebuyError::Config(format!(
+                            "another instance is running with PID {} (pidfile {})",
+                            pid,
+                            path.display()
+                        )))
+                    }
+                    None => {
+                        return Err(RebuyError::Config(format!(
+                            "pidfile {} names PID {}, whether it still runs cannot be checked on this system, remove the file if it does not",
+                            path.display(),
+                            pid
+                        )))
+                    }
+                    Some(false) => warn!(path = %path.display(), pid, "replacing stale pidfile"),
+                }
+                std::fs::remove_file(path).map_err(|e| pidfile_error(path, e))?;
+                create_new(path).map_err(|e| pidfile_error(path, e))?
+            }
+            Err(e) => return Err(pidfile_error(path, e)),
+        };
+        writeln!(file, "{}", std::process::id()).map_err(|e| pidfile_error(path, e))?;
+        Ok(Pidfile {
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+impl Drop for Pidfile {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            warn!(path = %self.path.display(), "unable to remove pidfile: {}", e);
+        }
+    }
+}
+
+/// Create the file at `path`, failing if it already exists.
+fn create_new(path: &Path) -> std::io::Result<File> {
+    OpenOptions::new().write(true).create_new(true).open(path)
+}
+
+fn pidfile_error(path: &Path, e: std::io::Error) -> RebuyError {
+    RebuyError::Config(format!("unable to write pidfile {}: {}", path.display(), e))
+}
+
+/// Whether a process with `pid` is running, `None` without `/proc` to look it up.
+fn is_running(pid: u32) -> Option<bool> {
+    if pid == std::process::id() {
+        return Some(false);
+    }
+    let proc = Path::new("/proc");
+    proc.is_dir().then(|| proc.join(pid.to_string()).exists())
+}
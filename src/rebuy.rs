@@ -0,0 +1,930 @@
+//! The rebuy check: buy rolls for the wallet addresses meeting the rebuy condition.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::stream::{self, StreamExt};
+use massa_models::api::AddressInfo;
+use massa_models::{Address, Amount, Operation, OperationId, OperationType};
+use serde::{Serialize, Serializer};
+use tokio::sync::Mutex;
+use tracing::{debug, error, info, warn};
+
+use crate::error::{RebuyError, Result};
+use crate::notify::Notifier;
+use crate::rpc::{FeeStrategy, Inclusion, NodeConfig, SendOptions, SentOperation};
+use crate::state::{self, RebuyState};
+use crate::wallet::Wallets;
+use crate::{rpc, telemetry};
+
+/// Log target of the sent roll buys, kept at info level by `--quiet`.
+pub const BUY_TARGET: &str = "roll_buy";
+
+/// With `buy_at_cycle_start`, buys are sent during the first `1 / CYCLE_START_FRACTION` of a cycle.
+const CYCLE_START_FRACTION: u64 = 4;
+
+/// Rolling window `Settings::max_daily_spend` applies to.
+const DAILY_SPEND_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Options driving the rebuy decision.
+pub struct Settings {
+    /// Balance required before buying, the node's roll price when `None`
+    pub min_balance: Option<Amount>,
+    pub roll_count: u64,
+    /// Wallet addresses the check is restricted to, all of them when empty
+    pub addresses: Vec<Address>,
+    /// Buy as many rolls as the balance affords instead of `roll_count`
+    pub greedy: bool,
+    /// Reinvest the balance above the reserve into rolls whatever the rolls owned, up to `max_rolls`.
+    /// Implies `greedy`
+    pub compound: bool,
+    /// Buy what is missing for addresses owning fewer candidate rolls, `roll_count` for addresses without rolls when `None`
+    pub min_rolls: Option<u64>,
+    /// Candidate rolls a buy never takes an address past, uncapped when `None`
+    pub max_rolls: Option<u64>,
+    /// Balance that must remain on the address after buying
+    pub reserve: Amount,
+    pub fee: FeeStrategy,
+    /// Highest fee an operation may pay, unbounded when `None`
+    pub max_fee: Option<Amount>,
+    pub dry_run: bool,
+    /// Only decide what to buy from the addresses: no node status read, no operation built
+    pub check_only: bool,
+    /// In dry run, print each signed operation as a JSON line on stdout
+    pub dry_run_json: bool,
+    /// Wait for the sent operations to be final, for at most this duration
+    pub wait: Option<Duration>,
+    pub notifier: Option<Notifier>,
+    /// Maximum number of addresses handled at the same time
+    pub max_concurrency: usize,
+    /// Extra attempts at sending an operation after a connection error
+    pub send_retries: u32,
+    /// Compute slots with the node's time rather than the local clock
+    pub clock_compensation: bool,
+    /// Time an address is skipped after a buy, unless its candidate rolls change
+    pub cooldown: Duration,
+    /// Where the last buys are persisted across restarts
+    pub state_file: PathBuf,
+    /// Register the key of an address for staking after buying its rolls
+    pub auto_stake: bool,
+    /// Re-register the keys of addresses owning rolls the node does not stake with
+    pub ensure_staking: bool,
+    /// Only buy during the first periods of a cycle, see `CYCLE_START_FRACTION`
+    pub buy_at_cycle_start: bool,
+    /// Only check the addresses the node stakes with
+    pub only_staking: bool,
+    /// Balance compared to the threshold instead of the one of each address, to rehearse a
+    /// configuration without funds. The operations are built as usual
+    pub simulated_balance: Option<Amount>,
+    /// Most spent on roll buys, fees included, within 24 hours
+    pub max_daily_spend: Option<Amount>,
+    /// Times the roll buys of a check are built again and resent after the node rejected them as expired
+    pub expired_retries: u32,
+    /// Percentage the fee of expired roll buys is raised by on each resubmission
+    pub fee_bump: u64,
+    /// Settings of specific addresses, taking precedence over the global ones
+    pub overrides: HashMap<Address, AddressOverrides>,
+    /// Checks the cached node configuration is used for before fetching it again
+    pub refresh_config_every: u64,
+    /// Roll price the node must report, to catch a node running on another network than intended
+    pub expected_roll_price: Option<Amount>,
+}
+
+impl Settings {
+    /// Options of the operations sent one at a time, e.g. by `sell_rolls`.
+    pub fn send_options(&self) -> SendOptions {
+        SendOptions {
+            fee: self.fee,
+            max_fee: self.max_fee,
+            dry_run: self.dry_run,
+            dry_run_json: self.dry_run_json,
+            send_retries: self.send_retries,
+            clock_compensation: self.clock_compensation,
+        }
+    }
+
+    /// Roll price a check-only pass decides with, without asking the node: the one of the network
+    /// preset, else `min_balance`.
+    pub fn check_only_roll_price(&self) -> Result<Amount> {
+        self.expected_roll_price.or(self.min_balance).ok_or_else(|| {
+            RebuyError::Config("--check-only needs --network or --min-balance to know the roll price".to_string())
+        })
+    }
+}
+
+/// Settings of a single address, each one replacing the global setting when present.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct AddressOverrides {
+    pub min_balance: Option<Amount>,
+    pub roll_count: Option<u64>,
+    pub max_rolls: Option<u64>,
+    /// Fixed fee paid by the roll buys of the address, whatever the fee strategy
+    pub fee: Option<Amount>,
+}
+
+/// Roll buy sent during a check, with no operation ID in dry run.
+#[derive(Debug, Serialize)]
+pub struct BuyReport {
+    pub address: Address,
+    pub roll_count: u64,
+    /// Price of the rolls and fee
+    pub cost: Amount,
+    pub operation_ids: Vec<OperationId>,
+    /// Block each operation was included in, filled by `--wait`
+    pub inclusions: Vec<Inclusion>,
+}
+
+/// Roll buy that failed during a check.
+#[derive(Debug, Serialize)]
+pub struct BuyFailure {
+    pub address: Address,
+    #[serde(serialize_with = "serialize_error")]
+    pub error: RebuyError,
+}
+
+/// Address that needed rolls but whose balance did not cover one.
+#[derive(Debug, Serialize)]
+pub struct Underfunded {
+    pub address: Address,
+    pub balance: Amount,
+    /// Balance needed to buy a roll
+    pub required: Amount,
+}
+
+/// Outcome of the check of an address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum Decision {
+    /// Rolls were bought, or would have been in dry run or check-only
+    Bought,
+    /// The address owns enough rolls
+    SkippedHasRolls,
+    /// The balance does not cover the threshold or a roll
+    SkippedInsufficient,
+    /// A roll buy was sent recently, see `Settings::cooldown`
+    SkippedCooldown,
+    /// The roll buys of the last 24 hours reached `Settings::max_daily_spend`
+    SkippedBudget,
+    /// The wallet has no public key to send operations from the address
+    SkippedNoPublicKey,
+    /// The roll buy failed, see `RebuyReport::failures`
+    Error,
+}
+
+impl fmt::Display for Decision {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Decision::Bought => "bought",
+            Decision::SkippedHasRolls => "has rolls",
+            Decision::SkippedInsufficient => "insufficient",
+            Decision::SkippedCooldown => "cooldown",
+            Decision::SkippedBudget => "budget",
+            Decision::SkippedNoPublicKey => "no public key",
+            Decision::Error => "error",
+        })
+    }
+}
+
+/// State of an address when it was checked, and what the check decided for it.
+#[derive(Debug, Serialize)]
+pub struct AddressReport {
+    pub address: Address,
+    /// Final balance reported by the node
+    pub balance: Amount,
+    pub candidate_rolls: u64,
+    pub decision: Decision,
+    /// Rolls bought, 0 unless the decision is `Bought` or `Error`
+    pub roll_count: u64,
+    /// IDs of the sent operations, none in dry run
+    pub operation_ids: Vec<OperationId>,
+}
+
+/// What a rebuy check looked at and did.
+#[derive(Debug, Default, Serialize)]
+pub struct RebuyReport {
+    /// Milliseconds since the unix epoch when the check started
+    pub timestamp: u64,
+    /// Wallet addresses returned by the node
+    pub addresses: Vec<Address>,
+    /// Decision taken for each address with a public key, in the order they were checked
+    pub decisions: Vec<AddressReport>,
+    pub buys: Vec<BuyReport>,
+    pub failures: Vec<BuyFailure>,
+    pub underfunded: Vec<Underfunded>,
+}
+
+impl RebuyReport {
+    /// Record the `decision` taken for an address.
+    fn decide(&mut self, address_info: &AddressInfo, decision: Decision, roll_count: u64) {
+        self.decisions.push(AddressReport {
+            address: address_info.address,
+            balance: address_info.ledger_info.final_ledger_info.balance,
+            candidate_rolls: address_info.rolls.candidate_rolls,
+            decision,
+            roll_count,
+            operation_ids: Vec::new(),
+        });
+    }
+
+    /// Update the decision recorded for `address` once its roll buy is sent or failed.
+    fn update(&mut self, address: Address, decision: Decision, operation_ids: &[OperationId]) {
+        if let Some(report) = self.decisions.iter_mut().find(|report| report.address == address) {
+            report.decision = decision;
+            report.operation_ids = operation_ids.to_vec();
+        }
+    }
+}
+
+/// Serialize an error as its message.
+fn serialize_error<S: Serializer>(error: &RebuyError, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+    serializer.collect_str(&format_args!("{:#}", error))
+}
+
+/// Roll buy of a check once sent and handled by `after_buy`.
+struct SentBuy {
+    address: Address,
+    roll_count: u64,
+    /// Candidate rolls of the address when the buy was decided
+    candidate_rolls: u64,
+    /// Price of the rolls and fee
+    cost: Amount,
+    /// ID of the operation accepted by the node, none in dry run
+    operation_ids: Vec<OperationId>,
+    /// Block each operation was included in, or why handling the buy failed
+    inclusions: Result<Vec<Inclusion>>,
+}
+
+/// Node configuration cached between checks, with the number of checks it served.
+struct CachedConfig {
+    node_config: NodeConfig,
+    checks: u64,
+}
+
+/// Runs the rebuy check of wallets against a node.
+pub struct RebuyClient {
+    client: rpc::Client,
+    wallets: Mutex<Arc<Wallets>>,
+    settings: Settings,
+    state: Mutex<RebuyState>,
+    node_config: Mutex<Option<CachedConfig>>,
+}
+
+impl RebuyClient {
+    /// Start with an empty state, see `load_state` to resume from the state file.
+    pub fn new(client: rpc::Client, wallets: Wallets, settings: Settings) -> RebuyClient {
+        RebuyClient {
+            client,
+            wallets: Mutex::new(Arc::new(wallets)),
+            settings,
+            state: Mutex::new(RebuyState::default()),
+            node_config: Mutex::new(None),
+        }
+    }
+
+    /// Wallets the operations are signed with.
+    async fn wallets(&self) -> Arc<Wallets> {
+        self.wallets.lock().await.clone()
+    }
+
+    /// Load the wallet files again, the next checks using the keys added or removed since.
+    /// On failure, the loaded wallets are kept.
+    pub async fn reload_wallets(&self) -> Result<()> {
+        let mut wallets = self.wallets.lock().await;
+        let reloaded = wallets.reload()?;
+        let (before, after) = (wallets.addresses().len(), reloaded.addresses().len());
+        if before != after {
+            info!(before, after, "wallet addresses changed");
+        }
+        *wallets = Arc::new(reloaded);
+        Ok(())
+    }
+
+    /// Fetch the node configuration and check operations can be built from it.
+    /// It is then cached for the next `Settings::refresh_config_every` checks.
+    pub async fn check_node_config(&self) -> Result<()> {
+        let node_config = self.fetch_node_config().await?;
+        *self.node_config.lock().await = Some(CachedConfig { node_config, checks: 0 });
+        Ok(())
+    }
+
+    /// Fetch the node configuration, rejecting the ones operations cannot be built with.
+    async fn fetch_node_config(&self) -> Result<NodeConfig> {
+        let node_status = match self.client.public.get_status().await {
+            Ok(node_status) => node_status,
+            Err(e) => rpc_error!(e),
+        };
+        let node_config = NodeConfig::from_status(&node_status, self.settings.clock_compensation)?;
+        match self.settings.expected_roll_price {
+            Some(expected) if expected != node_config.config.roll_price => Err(RebuyError::Config(format!(
+                "the node reports a roll price of {} instead of the expected {}, is it on the right network?",
+                node_config.config.roll_price, expected
+            ))),
+            _ => Ok(node_config),
+        }
+    }
+
+    /// Cached node configuration, fetched again once it served `Settings::refresh_config_every`
+    /// checks or after a failed check.
+    async fn node_config(&self) -> Result<NodeConfig> {
+        let mut cached = self.node_config.lock().await;
+        if let Some(cached) = cached.as_mut() {
+            if cached.checks < self.settings.refresh_config_every {
+                cached.checks += 1;
+                return Ok(cached.node_config.clone());
+            }
+        }
+        let node_config = self.fetch_node_config().await?;
+        debug!("node configuration refreshed");
+        *cached = Some(CachedConfig {
+            node_config: node_config.clone(),
+            checks: 1,
+        });
+        Ok(node_config)
+    }
+
+    /// Load the state left by a previous run and check whether its pending buys landed.
+    pub async fn load_state(&self) -> Result<()> {
+        let mut state = RebuyState::load(&self.settings.state_file)?;
+        // a check-only pass only reads the addresses from the node
+        if !self.settings.check_only {
+            state.reconcile(&self.client).await;
+        }
+        *self.state.lock().await = state;
+        Ok(())
+    }
+
+    /// Fetch the wallet addresses from the node and buy rolls for each one meeting the rebuy condition.
+    /// Fails if the node cannot be queried, a failed roll buy is only listed in the report failures.
+    pub async fn check_and_rebuy(&self) -> Result<RebuyReport> {
+        let result = self.run_check().await;
+        // the node may have been reconfigured or replaced since the configuration was cached
+        if !matches!(&result, Ok(report) if report.failures.is_empty()) {
+            *self.node_config.lock().await = None;
+        }
+        result
+    }
+
+    /// Body of `check_and_rebuy`.
+    async fn run_check(&self) -> Result<RebuyReport> {
+        let settings = &self.settings;
+        let mut report = RebuyReport {
+            timestamp: state::now(),
+            ..RebuyReport::default()
+        };
+        // the same wallets for the whole check, even if they are reloaded meanwhile
+        let wallets = self.wallets().await;
+        let mut wallet_addresses = fetch_wallet_addresses(&self.client, &wallets, &settings.addresses).await?;
+        report.addresses = wallet_addresses.iter().map(|address_info| address_info.address).collect();
+        if settings.ensure_staking && !settings.check_only {
+            self.ensure_staking(&wallet_addresses).await?;
+        }
+        if settings.only_staking {
+            let staking_addresses = match self.client.private.get_staking_addresses().await {
+                Ok(staking_addresses) => staking_addresses,
+                Err(e) => rpc_error!(e),
+            };
+            wallet_addresses.retain(|address_info| {
+                let staking = staking_addresses.contains(&address_info.address);
+                if !staking {
+                    info!(address = %address_info.address, "address not staked by the node, excluded by --only-staking");
+                }
+                staking
+            });
+        }
+        // a check-only pass does not read the node status, see `check_only_roll_price`
+        let node_config = if settings.check_only { None } else { Some(self.node_config().await?) };
+        let current_slot = node_config.as_ref().map(NodeConfig::current_slot).transpose()?.flatten();
+        // the roll price depends on the network the node runs on
+        let roll_price = match &node_config {
+            Some(node_config) => node_config.config.roll_price,
+            None => settings.check_only_roll_price()?,
+        };
+        let min_balance = settings.min_balance.unwrap_or(roll_price);
+        if let (true, Some(node_config)) = (settings.buy_at_cycle_start, &node_config) {
+            let cfg = &node_config.config;
+            let period = current_slot.map_or(0, |slot| slot.period);
+            let cycle = period / cfg.periods_per_cycle;
+            let position = period % cfg.periods_per_cycle;
+            if position >= (cfg.periods_per_cycle / CYCLE_START_FRACTION).max(1) {
+                info!(
+                    cycle,
+                    target_cycle = cycle + 1,
+                    periods_left = cfg.periods_per_cycle - position,
+                    "deferring roll buys to the start of the next cycle"
+                );
+                return Ok(report);
+            }
+            info!(cycle, position, "start of the cycle, roll buys allowed");
+        }
+        if let Some(simulated_balance) = settings.simulated_balance {
+            warn!(%simulated_balance, dry_run = settings.dry_run, "balance simulation active, the balances of the addresses are ignored");
+        }
+        // the fee is paid from the same balance as the rolls, a check-only pass assumes the highest one
+        // rather than reading the node status
+        let fee = match &node_config {
+            None => settings.fee.max(),
+            Some(node_config) => {
+                // operations built from a stale view of the chain would expire before being included,
+                // a daemon tries again on its next check
+                match rpc::check_synced(&self.client.public, node_config).await {
+                    Ok(()) => {}
+                    Err(e @ RebuyError::NotSynced { .. }) => {
+                        warn!("{}, skipping the check", e);
+                        return Ok(report);
+                    }
+                    Err(e) => return Err(e),
+                }
+                match settings.fee {
+                    FeeStrategy::Fixed(fee) => fee,
+                    // only the dynamic fee needs the current pool size
+                    FeeStrategy::Dynamic { .. } => {
+                        let node_status = match self.client.public.get_status().await {
+                            Ok(node_status) => node_status,
+                            Err(e) => rpc_error!(e),
+                        };
+                        settings.fee.fee(&node_status)
+                    }
+                }
+            }
+        };
+        let mut state = self.state.lock().await;
+        // what is left of --max-daily-spend, in raw amount
+        let mut budget = settings
+            .max_daily_spend
+            .map(|max| max.to_raw().saturating_sub(state.spent_within(DAILY_SPEND_WINDOW).to_raw()));
+        let mut purchases = Vec::new();
+        for address_info in &wallet_addresses {
+            let address = address_info.address;
+            if wallets.find_associated_public_key(address).is_none() {
+                warn!(%address, "no public key in the wallet for this address, skipping it");
+                report.decide(address_info, Decision::SkippedNoPublicKey, 0);
+                continue;
+            }
+            // the settings of the address, if any, take precedence over the global ones
+            let overrides = settings.overrides.get(&address).copied().unwrap_or_default();
+            let rolls_per_buy = overrides.roll_count.unwrap_or(settings.roll_count);
+            let max_rolls = overrides.max_rolls.or(settings.max_rolls);
+            let min_balance = overrides.min_balance.unwrap_or(min_balance);
+            let fee = overrides.fee.unwrap_or(fee);
+            let balance = address_info.ledger_info.final_ledger_info.balance;
+            telemetry::address_state(address, address_info.rolls.candidate_rolls, balance);
+            let balance = settings.simulated_balance.unwrap_or(balance);
+            let candidate_rolls = address_info.rolls.candidate_rolls;
+            let final_rolls = address_info.rolls.final_rolls;
+            if final_rolls != candidate_rolls {
+                info!(%address, final_rolls, candidate_rolls, "roll buy or sell pending, the decision uses the candidate rolls");
+            }
+            // --max-rolls only caps the buys, an address already there is never bought for
+            if max_rolls.is_some_and(|max_rolls| candidate_rolls >= max_rolls) {
+                report.decide(address_info, Decision::SkippedHasRolls, 0);
+                continue;
+            }
+            // below the floor, buy what is missing to reach it (--roll-count without --min-rolls),
+            // above it, only --compound keeps buying
+            let floor = settings.min_rolls.unwrap_or(1);
+            let requested = if candidate_rolls < floor {
+                match settings.min_rolls {
+                    Some(min_rolls) => min_rolls - candidate_rolls,
+                    None => rolls_per_buy,
+                }
+            } else if settings.compound {
+                rolls_per_buy
+            } else {
+                report.decide(address_info, Decision::SkippedHasRolls, 0);
+                continue;
+            };
+            if balance < min_balance {
+                info!(%address, %balance, required = %min_balance, "balance below the rebuy threshold, skipping");
+                report.underfunded.push(Underfunded {
+                    address,
+                    balance,
+                    required: min_balance,
+                });
+                report.decide(address_info, Decision::SkippedInsufficient, 0);
+                continue;
+            }
+            if state.is_cooling_down(address, address_info.rolls.candidate_rolls, settings.cooldown) {
+                info!(%address, "roll buy sent recently, waiting for the node to reflect it");
+                report.decide(address_info, Decision::SkippedCooldown, 0);
+                continue;
+            }
+
+            let greedy = settings.greedy || settings.compound;
+            let wanted = if greedy { u64::MAX } else { requested };
+            let wanted = match max_rolls {
+                Some(max_rolls) => wanted.min(max_rolls.saturating_sub(candidate_rolls)),
+                None => wanted,
+            };
+            let mut roll_count = affordable_rolls(balance, settings.reserve, fee, roll_price, wanted);
+            if roll_count == 0 {
+                info!(%address, %balance, %roll_price, %fee, reserve = %settings.reserve, "balance does not cover a roll");
+                report.underfunded.push(Underfunded {
+                    address,
+                    balance,
+                    required: Amount::from_raw(
+                        roll_price
+                            .to_raw()
+                            .saturating_add(fee.to_raw())
+                            .saturating_add(settings.reserve.to_raw()),
+                    ),
+                });
+                report.decide(address_info, Decision::SkippedInsufficient, 0);
+                continue;
+            }
+            if roll_count < wanted && !greedy {
+                warn!(%address, %balance, roll_count, requested = wanted, "balance only covers part of the requested rolls");
+            }
+            if let Some(budget) = budget.as_mut() {
+                let covered = budget
+                    .saturating_sub(fee.to_raw())
+                    .checked_div(roll_price.to_raw())
+                    .unwrap_or(roll_count);
+                if covered == 0 {
+                    info!(%address, budget_left = %Amount::from_raw(*budget), "--max-daily-spend reached, skipping");
+                    report.decide(address_info, Decision::SkippedBudget, 0);
+                    continue;
+                }
+                if covered < roll_count {
+                    warn!(%address, roll_count = covered, requested = roll_count, "--max-daily-spend only covers part of the rolls");
+                    roll_count = covered;
+                }
+                *budget = budget.saturating_sub(buy_cost(roll_price, roll_count, fee));
+            }
+            let residual = Amount::from_raw(
+                balance
+                    .to_raw()
+                    .saturating_sub(roll_price.to_raw().saturating_mul(roll_count))
+                    .saturating_sub(fee.to_raw()),
+            );
+            info!(%address, %balance, roll_count, %residual, "buying rolls");
+            report.decide(address_info, Decision::Bought, roll_count);
+            purchases.push((address, roll_count, fee, address_info.rolls.candidate_rolls));
+        }
+        // a check-only pass stops at the decisions
+        let node_config = match node_config {
+            Some(node_config) => node_config,
+            None => return Ok(report),
+        };
+
+        // build every operation, then send them all in a single call
+        let mut operations = Vec::new();
+        let mut sent = Vec::new();
+        for (address, roll_count, fee, candidate_rolls) in purchases {
+            telemetry::rebuy_attempt();
+            let op = OperationType::RollBuy { roll_count };
+            match rpc::build_operation(&node_config, &wallets, op, fee, settings.max_fee, address) {
+                Ok(operation) => {
+                    operations.push(operation);
+                    sent.push((address, roll_count, candidate_rolls));
+                }
+                Err(e) => {
+                    error!(%address, "roll buy failed: {:#}", e);
+                    report.update(address, Decision::Error, &[]);
+                    report.failures.push(BuyFailure { address, error: e });
+                }
+            }
+        }
+        if operations.is_empty() {
+            return Ok(report);
+        }
+        let operation_ids = if settings.dry_run {
+            for ((address, ..), operation) in sent.iter().zip(&operations) {
+                rpc::log_dry_run(*address, operation, settings.dry_run_json)?;
+            }
+            Vec::new()
+        } else {
+            let mut resubmissions = 0;
+            loop {
+                match rpc::send_operations(&self.client.public, operations.clone(), settings.send_retries).await {
+                    Ok(operation_ids) => break operation_ids,
+                    Err(RebuyError::OperationRejected(message))
+                        if rpc::is_expired_rejection(&message) && resubmissions < settings.expired_retries =>
+                    {
+                        resubmissions += 1;
+                        warn!(
+                            resubmissions,
+                            expired_retries = settings.expired_retries,
+                            fee_bump = settings.fee_bump,
+                            "operations expired before being accepted ({}), resubmitting them with a fresh expire period",
+                            message
+                        );
+                        operations = self.rebuild_operations(&node_config, &wallets, &sent, &operations)?;
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+        };
+
+        // the node answers with its own ordering of the IDs: tie each operation to the ID computed
+        // from its content, only kept when the node accepted it
+        let accepted: HashSet<OperationId> = operation_ids.into_iter().collect();
+        let mut sent_ids = Vec::with_capacity(operations.len());
+        for operation in &operations {
+            let operation_id = operation
+                .get_operation_id()
+                .map_err(|e| RebuyError::Operation(format!("unable to compute the operation ID: {}", e)))?;
+            sent_ids.push(accepted.contains(&operation_id).then_some(operation_id));
+        }
+        let unknown = accepted.len() - sent_ids.iter().flatten().count();
+        if unknown > 0 {
+            warn!(unknown, "the node returned operation IDs matching none of the sent operations");
+        }
+        // an operation the node left out was not sent, whatever the other ones became
+        let mut accepted_buys = Vec::with_capacity(sent.len());
+        for (((address, roll_count, candidate_rolls), operation), operation_id) in sent.into_iter().zip(operations).zip(sent_ids) {
+            if operation_id.is_none() && !settings.dry_run {
+                let e = RebuyError::OperationRejected("not accepted by the node".to_string());
+                error!(%address, "roll buy failed: {:#}", e);
+                report.update(address, Decision::Error, &[]);
+                report.failures.push(BuyFailure { address, error: e });
+                continue;
+            }
+            let cost = Amount::from_raw(buy_cost(roll_price, roll_count, operation.content.fee));
+            if operation_id.is_some() {
+                state.record_spend(cost);
+            }
+            accepted_buys.push((address, roll_count, candidate_rolls, cost, operation, operation_id));
+        }
+
+        // addresses are independent: handle them concurrently and report every failure
+        let results: Vec<SentBuy> = stream::iter(accepted_buys)
+            .map(|(address, roll_count, candidate_rolls, cost, operation, operation_id)| {
+                let operation_ids: Vec<OperationId> = operation_id.into_iter().collect();
+                async move {
+                    if !operation_ids.is_empty() {
+                        info!(target: BUY_TARGET, %address, roll_count, fee = %operation.content.fee, expire_period = operation.content.expire_period, "roll buy sent");
+                    }
+                    let inclusions = self.after_buy(address, roll_count, operation.content.fee, &operation_ids).await;
+                    SentBuy {
+                        address,
+                        roll_count,
+                        candidate_rolls,
+                        cost,
+                        operation_ids,
+                        inclusions,
+                    }
+                }
+            })
+            .buffer_unordered(settings.max_concurrency)
+            .collect()
+            .await;
+        let mut bought = false;
+        for SentBuy {
+            address,
+            roll_count,
+            candidate_rolls,
+            cost,
+            operation_ids,
+            inclusions,
+        } in results
+        {
+            if !operation_ids.is_empty() {
+                state.record_buy(address, current_slot, candidate_rolls, operation_ids.clone());
+                bought = true;
+            }
+            match inclusions {
+                Ok(inclusions) => {
+                    report.update(address, Decision::Bought, &operation_ids);
+                    report.buys.push(BuyReport {
+                        address,
+                        roll_count,
+                        cost,
+                        operation_ids,
+                        inclusions,
+                    });
+                }
+                Err(e) => {
+                    error!(%address, "roll buy failed: {:#}", e);
+                    report.update(address, Decision::Error, &operation_ids);
+                    report.failures.push(BuyFailure { address, error: e });
+                }
+            }
+        }
+        // the operations are sent whatever happens to the file, the report must still tell so
+        if bought {
+            if let Err(e) = state.save(&settings.state_file) {
+                warn!(state_file = %settings.state_file.display(), "unable to save the state, a restart may buy again: {}", e);
+            }
+        }
+        Ok(report)
+    }
+
+    /// Sell `roll_count` rolls of the first wallet address, refusing to sell more than it owns.
+    /// Returns the IDs of the sent operations.
+    pub async fn sell_rolls(&self, roll_count: u64) -> Result<Vec<OperationId>> {
+        let settings = &self.settings;
+        let wallets = self.wallets().await;
+        let wallet_addresses = fetch_wallet_addresses(&self.client, &wallets, &[]).await?;
+        let address_info = match wallet_addresses.first() {
+            Some(address_info) => address_info,
+            None => return Err(RebuyError::WalletLoad("no wallet address known by the node".to_string())),
+        };
+        let owned = address_info.rolls.candidate_rolls;
+        if roll_count > owned {
+            return Err(RebuyError::InsufficientRolls {
+                address: address_info.address,
+                owned,
+                requested: roll_count,
+            });
+        }
+        info!(address = %address_info.address, roll_count, owned, "selling rolls");
+        let SentOperation { operation_ids, .. } = rpc::send_operation(&self.client.public, &wallets, OperationType::RollSell { roll_count }, address_info.address, &settings.send_options()).await?;
+        if let Some(timeout) = settings.wait {
+            rpc::wait_for_final(&self.client, operation_ids.clone(), timeout).await?;
+        }
+        Ok(operation_ids)
+    }
+
+    /// Send `amount` from `from`, or the first wallet address, to `recipient`, refusing to send more
+    /// than the final balance minus the fee. Returns the IDs of the sent operations.
+    pub async fn transfer(&self, from: Option<Address>, recipient: Address, amount: Amount) -> Result<Vec<OperationId>> {
+        let settings = &self.settings;
+        let only: Vec<Address> = from.into_iter().collect();
+        let wallets = self.wallets().await;
+        let wallet_addresses = fetch_wallet_addresses(&self.client, &wallets, &only).await?;
+        let address_info = match wallet_addresses.first() {
+            Some(address_info) => address_info,
+            None => return Err(RebuyError::WalletLoad("no wallet address known by the node".to_string())),
+        };
+        if recipient == address_info.address {
+            return Err(RebuyError::Config(format!("cannot transfer from {} to itself", recipient)));
+        }
+        let balance = address_info.ledger_info.final_ledger_info.balance;
+        let required = amount.to_raw().saturating_add(settings.fee.max().to_raw());
+        if balance.to_raw() < required {
+            return Err(RebuyError::InsufficientBalance {
+                address: address_info.address,
+                balance,
+                required: Amount::from_raw(required),
+            });
+        }
+        info!(address = %address_info.address, %recipient, %amount, "sending transfer");
+        let op = OperationType::Transaction {
+            recipient_address: recipient,
+            amount,
+        };
+        let SentOperation { operation_ids, .. } = rpc::send_operation(&self.client.public, &wallets, op, address_info.address, &settings.send_options()).await?;
+        if let Some(timeout) = settings.wait {
+            rpc::wait_for_final(&self.client, operation_ids.clone(), timeout).await?;
+        }
+        Ok(operation_ids)
+    }
+
+    /// Notify the roll buy sent for `address`, then register its key for staking and wait for the
+    /// operation if configured, returning the block it was included in.
+    async fn after_buy(&self, address: Address, roll_count: u64, fee: Amount, operation_ids: &[OperationId]) -> Result<Vec<Inclusion>> {
+        let settings = &self.settings;
+        if let (Some(notifier), false) = (&settings.notifier, operation_ids.is_empty()) {
+            notifier.roll_buy(address, roll_count, fee, operation_ids).await;
+        }
+        if settings.auto_stake && !settings.dry_run {
+            self.register_staking(address).await?;
+        }
+        match settings.wait {
+            Some(timeout) => rpc::wait_for_final(&self.client, operation_ids.to_vec(), timeout).await,
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Re-register the keys of the wallet addresses owning rolls that the node does not stake with,
+    /// e.g. after a node restart that lost its staking keys.
+    async fn ensure_staking(&self, wallet_addresses: &[AddressInfo]) -> Result<()> {
+        let staking_addresses = match self.client.private.get_staking_addresses().await {
+            Ok(staking_addresses) => staking_addresses,
+            Err(e) => rpc_error!(e),
+        };
+        let missing: Vec<Address> = wallet_addresses
+            .iter()
+            .filter(|address_info| address_info.rolls.candidate_rolls > 0 || address_info.rolls.active_rolls > 0)
+            .map(|address_info| address_info.address)
+            .filter(|address| !staking_addresses.contains(address))
+            .collect();
+        let wallets = self.wallets().await;
+        let private_keys = missing
+            .iter()
+            .filter_map(|address| wallets.private_key(*address))
+            .collect::<Vec<_>>();
+        if private_keys.is_empty() {
+            return Ok(());
+        }
+        if let Err(e) = self.client.private.add_staking_private_keys(private_keys).await {
+            rpc_error!(e);
+        }
+        for address in missing {
+            warn!(%address, "address owning rolls was not staking, registered its key again");
+        }
+        Ok(())
+    }
+
+    /// Build `operations`, sent for `sent`, again with a fresh expire period and their fee raised
+    /// by `Settings::fee_bump` percent.
+    fn rebuild_operations(
+        &self,
+        node_config: &NodeConfig,
+        wallets: &Wallets,
+        sent: &[(Address, u64, u64)],
+        operations: &[Operation],
+    ) -> Result<Vec<Operation>> {
+        sent.iter()
+            .zip(operations)
+            .map(|((address, roll_count, _), operation)| {
+                // widened first, any --fee-bump saturating rather than overflowing
+                let fee = (operation.content.fee.to_raw() as u128).saturating_mul(100 + self.settings.fee_bump as u128) / 100;
+                let fee = Amount::from_raw(fee.min(u64::MAX as u128) as u64);
+                if let Some(max_fee) = self.settings.max_fee.filter(|max_fee| fee > *max_fee) {
+                    return Err(RebuyError::Config(format!(
+                        "fee {} of the resubmitted operation of {}, raised by --fee-bump, is above the maximum fee {}, pass --allow-high-fee to pay it",
+                        fee, address, max_fee
+                    )));
+                }
+                let op = OperationType::RollBuy { roll_count: *roll_count };
+                rpc::build_operation(node_config, wallets, op, fee, self.settings.max_fee, *address)
+            })
+            .collect()
+    }
+
+    /// Register the private key of `address` with the node so it stakes with its rolls,
+    /// then check the node reports it among its staking addresses.
+    async fn register_staking(&self, address: Address) -> Result<()> {
+        let private_key = match self.wallets().await.private_key(address) {
+            Some(private_key) => private_key,
+            None => return Err(RebuyError::MissingPrivateKey(address)),
+        };
+        if let Err(e) = self.client.private.add_staking_private_keys(vec![private_key]).await {
+            rpc_error!(e);
+        }
+        let staking_addresses = match self.client.private.get_staking_addresses().await {
+            Ok(staking_addresses) => staking_addresses,
+            Err(e) => rpc_error!(e),
+        };
+        if !staking_addresses.contains(&address) {
+            return Err(RebuyError::NotStaking(address));
+        }
+        info!(%address, "address registered for staking");
+        Ok(())
+    }
+}
+
+/// Fetch the information of the wallet addresses from the node, only those of `only` when not empty.
+/// The answer is returned in the order of the requested addresses. Fails on an empty wallet or an
+/// address of `only` missing from it, and warns about addresses the node did not return.
+pub async fn fetch_wallet_addresses(client: &rpc::Client, wallets: &Wallets, only: &[Address]) -> Result<Vec<AddressInfo>> {
+    let mut addresses: Vec<Address> = wallets.addresses();
+    if addresses.is_empty() {
+        return Err(RebuyError::WalletLoad("wallet contains no keys; add keys before running".to_string()));
+    }
+    if !only.is_empty() {
+        if let Some(address) = only.iter().find(|address| !addresses.contains(address)) {
+            return Err(RebuyError::Config(format!("address {} is not in the wallet", address)));
+        }
+        addresses = only.to_vec();
+    }
+    // the whole check depends on this call, a connection hiccup is worth a few retries
+    let returned = match rpc::with_backoff("fetch the wallet addresses", client.public.max_attempts(), || {
+        client.public.get_addresses(addresses.clone())
+    })
+    .await
+    {
+        Ok(returned) => returned,
+        Err(e) => rpc_error!(e),
+    };
+    // match the answer by address rather than by position, the node may omit or reorder some
+    let mut returned: HashMap<Address, AddressInfo> = returned
+        .into_iter()
+        .map(|address_info| (address_info.address, address_info))
+        .collect();
+    let mut wallet_addresses = Vec::with_capacity(addresses.len());
+    for address in &addresses {
+        match returned.remove(address) {
+            Some(address_info) => wallet_addresses.push(address_info),
+            None => warn!(%address, "address not returned by the node"),
+        }
+    }
+    for address in returned.keys() {
+        warn!(%address, "address returned by the node without being requested, ignoring it");
+    }
+    Ok(wallet_addresses)
+}
+
+/// Raw amount spent by buying `roll_count` rolls at `roll_price` with an operation paying `fee`.
+fn buy_cost(roll_price: Amount, roll_count: u64, fee: Amount) -> u64 {
+    roll_price
+        .to_raw()
+        .saturating_mul(roll_count)
+        .saturating_add(fee.to_raw())
+}
+
+/// Largest number of rolls, up to `wanted`, that `balance` can pay for at `roll_price` along
+/// with the operation `fee`, while keeping at least `reserve` on the address.
+fn affordable_rolls(balance: Amount, reserve: Amount, fee: Amount, roll_price: Amount, wanted: u64) -> u64 {
+    let spendable = balance
+        .to_raw()
+        .saturating_sub(reserve.to_raw())
+        .saturating_sub(fee.to_raw());
+    match spendable.checked_div(roll_price.to_raw()) {
+        Some(affordable) => affordable.min(wanted),
+        None => wanted,
+    }
+}
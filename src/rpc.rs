@@ -1,91 +1,641 @@
 // Copyright (c) 2021 MASSA LABS <info@massa.net>
 
+use async_trait::async_trait;
 use jsonrpc_core_client::transports::http;
-use jsonrpc_core_client::{RpcChannel, RpcResult, TypedClient};
+use jsonrpc_core_client::{RpcError, RpcResult, TypedClient};
 use massa_models::api::{
-    AddressInfo, BlockInfo, BlockSummary, EndorsementInfo, NodeStatus, OperationInfo, TimeInterval,
+    AddressInfo, BlockInfo, BlockSummary, EndorsementInfo, EventFilter, NodeStatus, OperationInfo,
+    TimeInterval,
 };
 use massa_models::clique::Clique;
+use massa_models::config::CompactConfig;
 use massa_models::composite::PubkeySig;
+use massa_models::output_event::SCOutputEvent;
 use massa_models::prehash::{Map, Set};
 use massa_models::{Address, BlockId, EndorsementId, Operation, OperationId, OperationType, Slot, OperationContent};
 use massa_signature::PrivateKey;
+use std::future::Future;
 use std::net::{IpAddr, SocketAddr};
-use anyhow::{Result, bail};
-use massa_wallet::Wallet;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 use massa_models::{Amount, timeslots::get_current_latest_block_slot};
+use tracing::{debug, info, warn};
 
+use crate::error::{RebuyError, Result};
+use crate::wallet::Wallets;
+
+/// Log target of the duration of each call to the node, logged at debug level.
+pub const RPC_TIMING_TARGET: &str = "rpc_timing";
+
+/// Upper bound of the delay between two connection attempts.
+const MAX_CONNECT_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Delay between two attempts at sending an operation.
+const SEND_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// Pool size, in operations, at which the dynamic fee reaches its maximum.
+const DYNAMIC_FEE_FULL_POOL: u64 = 10_000;
+
+/// Periods the latest final block may lag behind the current slot while the node is considered synced.
+/// Blocks take a couple of periods to become final on a healthy network.
+const SYNC_TOLERANCE_PERIODS: u64 = 5;
+
+/// Delay between two inclusion checks in `wait_for_final`.
+const WAIT_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Return the error of a failed call to the node: `RebuyError::OperationRejected` when it answered
+/// (e.g. an operation rejection), `RebuyError::Connection` otherwise.
 macro_rules! rpc_error {
-    ($e:expr) => {
-        bail!("check if your node is running: {}", $e)
-    };
+    ($e:expr) => {{
+        crate::telemetry::rpc_error();
+        return Err(crate::error::RebuyError::from($e));
+    }};
 }
 
-pub async fn send_operation(
-    client: &Client,
-    wallet: &Wallet,
+/// How the fee of an operation is chosen.
+#[derive(Debug, Clone, Copy)]
+pub enum FeeStrategy {
+    /// Always pay this fee
+    Fixed(Amount),
+    /// Pay up to `max`, proportionally to the number of operations waiting in the node pool
+    Dynamic { max: Amount },
+}
+
+impl FeeStrategy {
+    /// Highest fee this strategy may pay.
+    pub fn max(&self) -> Amount {
+        match *self {
+            FeeStrategy::Fixed(fee) | FeeStrategy::Dynamic { max: fee } => fee,
+        }
+    }
+
+    /// Fee to pay given the current state of the node.
+    /// The dynamic fee grows linearly with the pool size, reaching `max` at `DYNAMIC_FEE_FULL_POOL` operations.
+    pub fn fee(&self, status: &NodeStatus) -> Amount {
+        match *self {
+            FeeStrategy::Fixed(fee) => fee,
+            FeeStrategy::Dynamic { max } => {
+                let pool_size = status.pool_stats.operation_count.min(DYNAMIC_FEE_FULL_POOL);
+                let fee = max.to_raw() as u128 * pool_size as u128 / DYNAMIC_FEE_FULL_POOL as u128;
+                Amount::from_raw(fee as u64)
+            }
+        }
+    }
+}
+
+/// Node settings operations are built from, constant for a network and cached between checks.
+#[derive(Debug, Clone)]
+pub struct NodeConfig {
+    pub config: CompactConfig,
+    /// Milliseconds added to the local clock to match the node's, see `compute_clock_compensation`
+    pub clock_compensation: i64,
+}
+
+impl NodeConfig {
+    /// Extract the configuration from a node `status`, computing the clock compensation with the
+    /// node when `clock_compensation` is set. Rejects the configurations operations cannot be built with.
+    pub fn from_status(status: &NodeStatus, clock_compensation: bool) -> Result<NodeConfig> {
+        check_node_config(&status.config)?;
+        Ok(NodeConfig {
+            config: status.config.clone(),
+            clock_compensation: if clock_compensation { compute_clock_compensation(status) } else { 0 },
+        })
+    }
+
+    /// Current time in milliseconds since the unix epoch, seen from the node's clock.
+    fn now_millis(&self) -> u64 {
+        local_millis().saturating_add(self.clock_compensation).max(0) as u64
+    }
+
+    /// Latest slot at the current time, seen from the node's clock.
+    /// A failure reports the node settings it was computed from, to spot a bogus configuration.
+    pub fn current_slot(&self) -> Result<Option<Slot>> {
+        let cfg = &self.config;
+        get_current_latest_block_slot(cfg.thread_count, cfg.t0, cfg.genesis_timestamp, self.clock_compensation)
+            .map_err(|e| {
+                RebuyError::Slot(format!(
+                    "{} (thread_count {}, t0 {}, genesis_timestamp {}, clock compensation {}ms)",
+                    e, cfg.thread_count, cfg.t0, cfg.genesis_timestamp, self.clock_compensation
+                ))
+            })
+    }
+}
+
+/// Operation sent by `send_operation`, without IDs in dry run.
+pub struct SentOperation {
+    pub operation_ids: Vec<OperationId>,
+    /// Fee paid, as chosen by the `FeeStrategy`
+    pub fee: Amount,
+}
+
+/// Build and sign an operation of `addr` paying `fee`, expiring `operation_validity_periods`
+/// after the current slot computed from `node_config`.
+/// Refuses a fee above `max_fee`, unbounded when `None`.
+pub fn build_operation(
+    node_config: &NodeConfig,
+    wallets: &Wallets,
     op: OperationType,
     fee: Amount,
+    max_fee: Option<Amount>,
+    addr: Address,
+) -> Result<Operation> {
+    if let Some(max_fee) = max_fee.filter(|max_fee| fee > *max_fee) {
+        return Err(RebuyError::Config(format!(
+            "fee {} of the operation of {} is above the maximum fee {}, pass --allow-high-fee to pay it",
+            fee, addr, max_fee
+        )));
+    }
+    let cfg = &node_config.config;
+    let slot = node_config.current_slot()?;
+    let expire_period = expire_period(
+        slot,
+        addr.get_thread(cfg.thread_count),
+        cfg.operation_validity_periods,
+    );
+    // the operation is signed by the wallet holding the key of the address
+    let wallet = wallets.get(addr).ok_or(RebuyError::MissingPublicKey(addr))?;
+    let sender_public_key = match wallet.find_associated_public_key(addr) {
+        Some(pk) => *pk,
+        None => return Err(RebuyError::MissingPublicKey(addr)),
+    };
+
+    let operation = wallet
+        .create_operation(
+            OperationContent {
+                sender_public_key,
+                fee,
+                expire_period,
+                op,
+            },
+            addr,
+        )
+        .map_err(|e| RebuyError::Operation(e.to_string()))?;
+    validate_operation(&operation, slot)?;
+    Ok(operation)
+}
+
+/// Check a built `operation` is one the node would accept, to catch a construction bug without
+/// a round-trip: not expired at `slot`, moving a non-zero amount, signed by its sender key.
+pub fn validate_operation(operation: &Operation, slot: Option<Slot>) -> Result<()> {
+    let content = &operation.content;
+    if let Some(slot) = slot.filter(|slot| slot.period > content.expire_period) {
+        return Err(RebuyError::InvalidOperation(format!(
+            "expire period {} is before the current slot {}",
+            content.expire_period, slot
+        )));
+    }
+    match &content.op {
+        OperationType::RollBuy { roll_count: 0 } | OperationType::RollSell { roll_count: 0 } => {
+            return Err(RebuyError::InvalidOperation("roll count is 0".to_string()))
+        }
+        OperationType::Transaction { amount, .. } if amount.to_raw() == 0 => {
+            return Err(RebuyError::InvalidOperation("transaction amount is 0".to_string()))
+        }
+        _ => {}
+    }
+    operation
+        .verify_integrity()
+        .map_err(|e| RebuyError::InvalidOperation(format!("signature does not match the sender public key: {}", e)))?;
+    Ok(())
+}
+
+/// Log the operation `addr` would send, in place of sending it.
+/// With `json`, also print the whole signed operation (content and signature, the private key is
+/// not part of it) as a JSON line on stdout, as it would be sent to the node.
+pub fn log_dry_run(addr: Address, op: &Operation, json: bool) -> Result<()> {
+    info!(
+        address = %addr,
+        fee = %op.content.fee,
+        expire_period = op.content.expire_period,
+        "dry run, would send {:?}",
+        op.content.op
+    );
+    if json {
+        println!("{}", serde_json::to_string(op)?);
+    }
+    Ok(())
+}
+
+/// Send `operations` in a single call, retrying up to `send_retries` times after a connection error.
+/// Returns the IDs the node accepted, in no particular order.
+pub async fn send_operations<N: NodeApi>(
+    node: &N,
+    operations: Vec<Operation>,
+    send_retries: u32,
+) -> Result<Vec<OperationId>> {
+    let mut attempt = 0;
+    let operation_ids = loop {
+        match node.send_operations(operations.clone()).await {
+            Ok(operation_ids) => break operation_ids,
+            Err(e) if attempt < send_retries && is_transient(&e) => {
+                attempt += 1;
+                warn!(
+                    operations = operations.len(),
+                    attempt,
+                    send_retries,
+                    "unable to send operations: {}, retrying in {}s",
+                    e,
+                    SEND_RETRY_DELAY.as_secs()
+                );
+                tokio::time::sleep(SEND_RETRY_DELAY).await;
+            }
+            Err(e) => rpc_error!(e),
+        }
+    };
+    for _ in &operation_ids {
+        crate::telemetry::operation_sent();
+    }
+    Ok(operation_ids)
+}
+
+/// How `send_operation` builds and sends an operation.
+#[derive(Debug, Clone, Copy)]
+pub struct SendOptions {
+    pub fee: FeeStrategy,
+    /// Highest fee the operation may pay, unbounded when `None`
+    pub max_fee: Option<Amount>,
+    /// Log the operation instead of sending it
+    pub dry_run: bool,
+    /// In dry run, also print the signed operation as a JSON line on stdout
+    pub dry_run_json: bool,
+    /// Extra attempts at sending after a connection error
+    pub send_retries: u32,
+    /// Align the current slot on the node's clock, see `compute_clock_compensation`
+    pub clock_compensation: bool,
+}
+
+/// Build, sign and send a single operation of `addr`.
+pub async fn send_operation<N: NodeApi>(
+    node: &N,
+    wallets: &Wallets,
+    op: OperationType,
     addr: Address,
-    json: bool,
-) -> Result<()> {
-    let cfg = match client.0.get_status().await {
+    options: &SendOptions,
+) -> Result<SentOperation> {
+    let status = match node.get_status().await {
         Ok(node_status) => node_status,
         Err(e) => rpc_error!(e),
+    };
+    let node_config = NodeConfig::from_status(&status, options.clock_compensation)?;
+    let op = build_operation(&node_config, wallets, op, options.fee.fee(&status), options.max_fee, addr)?;
+    let fee = op.content.fee;
+    let expire_period = op.content.expire_period;
+    if options.dry_run {
+        log_dry_run(addr, &op, options.dry_run_json)?;
+        return Ok(SentOperation {
+            operation_ids: Vec::new(),
+            fee,
+        });
     }
-    .config;
 
-    let slot = get_current_latest_block_slot(cfg.thread_count, cfg.t0, cfg.genesis_timestamp, 0)? // clock compensation is zero
-        .unwrap_or_else(|| Slot::new(0, 0));
-    let mut expire_period = slot.period + cfg.operation_validity_periods;
-    if slot.thread >= addr.get_thread(cfg.thread_count) {
-        expire_period += 1;
+    let operation_ids = send_operations(node, vec![op], options.send_retries).await?;
+    info!(address = %addr, fee = %fee, expire_period, "operation sent");
+    Ok(SentOperation { operation_ids, fee })
+}
+
+/// Print the IDs of sent operations, as a JSON array or one per line.
+pub fn print_operation_ids(operation_ids: &[OperationId], json: bool) -> Result<()> {
+    if operation_ids.is_empty() {
+        return Ok(());
+    }
+    if json {
+        println!("{}", serde_json::to_string(operation_ids)?);
+    } else {
+        println!("Sent operation IDs:");
+        for operation_id in operation_ids {
+            println!("{}", operation_id);
+        }
+    }
+    Ok(())
+}
+
+/// Milliseconds to add to the local clock to match the time reported by the node,
+/// so slots are computed from the node's point of view on a machine with a skewed clock.
+/// The latency of the `get_status` call is not accounted for.
+pub fn compute_clock_compensation(status: &NodeStatus) -> i64 {
+    let compensation = status.current_time.to_millis() as i64 - local_millis();
+    debug!(compensation, "clock compensation with the node, in milliseconds");
+    compensation
+}
+
+/// Milliseconds since the unix epoch on the local clock.
+fn local_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as i64)
+        .unwrap_or_default()
+}
+
+/// Check the node follows the network: the latest final block it knows of must be within
+/// `SYNC_TOLERANCE_PERIODS` of the current slot computed from `node_config`. The slot of the node
+/// status comes from its clock rather than from the chain, so the final blocks of the graph over
+/// that window are fetched instead. A node without any is starting up or cut off from the network.
+pub async fn check_synced(node: &RpcClient, node_config: &NodeConfig) -> Result<()> {
+    let current_slot = match node_config.current_slot()? {
+        Some(current_slot) => current_slot,
+        // before genesis, there is nothing to follow yet
+        None => return Ok(()),
     };
-    let sender_public_key = match wallet.find_associated_public_key(addr) {
-        Some(pk) => *pk,
-        None => bail!("Missing public key"),
+    let last_final_slot = last_final_slots(node, node_config, SYNC_TOLERANCE_PERIODS)
+        .await?
+        .into_iter()
+        .flatten()
+        .max();
+    match last_final_slot {
+        Some(last_final_slot) if last_final_slot.period + SYNC_TOLERANCE_PERIODS >= current_slot.period => Ok(()),
+        last_final_slot => Err(RebuyError::NotSynced {
+            last_slot: last_final_slot.map_or_else(|| "none".to_string(), |slot| slot.to_string()),
+            current_slot: current_slot.to_string(),
+        }),
+    }
+}
+
+/// Slot of the latest final block of each thread among the blocks of the last `periods` periods,
+/// `None` for a thread without any.
+pub async fn last_final_slots(node: &RpcClient, node_config: &NodeConfig, periods: u64) -> Result<Vec<Option<Slot>>> {
+    let window = node_config.config.t0.to_millis().saturating_mul(periods);
+    let time_interval = TimeInterval {
+        start: Some(node_config.now_millis().saturating_sub(window).into()),
+        end: None,
+    };
+    let blocks = match node.get_graph_interval(time_interval).await {
+        Ok(blocks) => blocks,
+        Err(e) => rpc_error!(e),
     };
+    let mut slots = vec![None; node_config.config.thread_count as usize];
+    for block in blocks.iter().filter(|block| block.is_final) {
+        if let Some(last) = slots.get_mut(block.slot.thread as usize) {
+            *last = (*last).max(Some(block.slot));
+        }
+    }
+    Ok(slots)
+}
 
-    let op = wallet.create_operation(
-        OperationContent {
-            sender_public_key,
-            fee,
-            expire_period,
-            op,
-        },
-        addr,
-    )?;
-
-    match client.0.send_operations(vec![op]).await {
-        Ok(operation_ids) => {
-            if !json {
-                println!("Sent operation IDs:");
+/// Log the node settings the operations are built from and reject those they cannot be built with.
+pub fn check_node_config(cfg: &CompactConfig) -> Result<()> {
+    debug!(
+        thread_count = cfg.thread_count,
+        t0 = %cfg.t0,
+        operation_validity_periods = cfg.operation_validity_periods,
+        "node configuration"
+    );
+    if cfg.operation_validity_periods == 0 {
+        return Err(RebuyError::Config(
+            "the node reports operation_validity_periods = 0, operations sent to it would expire immediately".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Run `call` until it succeeds, retrying the errors coming from the connection (see `is_transient`)
+/// with an exponential backoff (1s, 2s, 4s... capped at 60s), up to `max_attempts` times in total.
+/// Each retry is logged, `what` describing the call.
+pub async fn with_backoff<T, F, Fut>(what: &str, max_attempts: u32, mut call: F) -> RpcResult<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = RpcResult<T>>,
+{
+    let mut delay = Duration::from_secs(1);
+    let mut attempt = 1;
+    loop {
+        match call().await {
+            Err(e) if attempt < max_attempts && is_transient(&e) => {
+                warn!(
+                    attempt,
+                    max_attempts,
+                    "unable to {}: {}, retrying in {}s",
+                    what,
+                    e,
+                    delay.as_secs()
+                );
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(MAX_CONNECT_BACKOFF);
+                attempt += 1;
             }
-            Ok(())
+            result => return result,
         }
-        Err(e) => rpc_error!(e),
     }
 }
 
-pub struct Client(pub RpcClient);
+/// Whether the node rejected an operation because its expire period has passed, in which case
+/// it can be built again with a fresh one.
+pub fn is_expired_rejection(message: &str) -> bool {
+    message.to_lowercase().contains("expire")
+}
+
+/// Whether `e` comes from the connection to the node rather than from the node answering,
+/// e.g. rejecting an invalid operation. Only those are worth retrying.
+fn is_transient(e: &RpcError) -> bool {
+    !matches!(e, RpcError::JsonRpcError(_) | RpcError::ParseError(..))
+}
+
+/// Last period an operation sent from an address of `addr_thread` is valid for.
+/// The current period is skipped if its slot for `addr_thread` has already passed.
+/// Without a current slot (before genesis), counts from `Slot::new(0, 0)`.
+pub fn expire_period(slot: Option<Slot>, addr_thread: u8, operation_validity_periods: u64) -> u64 {
+    let slot = slot.unwrap_or_else(|| Slot::new(0, 0));
+    let mut expire_period = slot.period + operation_validity_periods;
+    if slot.thread >= addr_thread {
+        expire_period += 1;
+    };
+    expire_period
+}
+
+/// Block a final operation was included in.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Inclusion {
+    pub operation_id: OperationId,
+    /// Final block holding the operation, `None` if the node could not tell which one
+    pub included_in_block: Option<BlockId>,
+}
+
+/// Poll the node until every operation of `operation_ids` is final or `timeout` elapses,
+/// then report the inclusion status of each one.
+/// Returns the block each operation was included in.
+pub async fn wait_for_final(
+    client: &Client,
+    operation_ids: Vec<OperationId>,
+    timeout: Duration,
+) -> Result<Vec<Inclusion>> {
+    let deadline = tokio::time::Instant::now() + timeout;
+    let mut pending = operation_ids;
+    let mut inclusions = Vec::with_capacity(pending.len());
+    if pending.is_empty() {
+        return Ok(inclusions);
+    }
+    loop {
+        match client.public.get_operations(pending.clone()).await {
+            Ok(operations) => {
+                for operation in operations.iter().filter(|operation| operation.is_final) {
+                    let included_in_block = final_block(client, &operation.in_blocks).await;
+                    match included_in_block {
+                        Some(block_id) => info!(operation_id = %operation.id, %block_id, "operation is final"),
+                        None => info!(operation_id = %operation.id, "operation is final"),
+                    }
+                    inclusions.push(Inclusion {
+                        operation_id: operation.id,
+                        included_in_block,
+                    });
+                }
+                pending.retain(|id| !operations.iter().any(|operation| operation.id == *id && operation.is_final));
+                if pending.is_empty() {
+                    return Ok(inclusions);
+                }
+                if tokio::time::Instant::now() >= deadline {
+                    for id in &pending {
+                        match operations.iter().find(|operation| operation.id == *id) {
+                            Some(operation) if !operation.in_blocks.is_empty() => {
+                                warn!(operation_id = %id, "operation included in a block but not final yet")
+                            }
+                            Some(operation) if operation.in_pool => warn!(operation_id = %id, "operation still in pool"),
+                            _ => warn!(operation_id = %id, "operation unknown to the node"),
+                        }
+                    }
+                    return Err(RebuyError::NotFinal {
+                        pending: pending.len(),
+                        timeout: timeout.as_secs(),
+                    });
+                }
+            }
+            Err(e) => {
+                if tokio::time::Instant::now() >= deadline {
+                    rpc_error!(e);
+                }
+                warn!("unable to fetch the operations status: {}", e);
+            }
+        }
+        tokio::time::sleep(WAIT_POLL_INTERVAL).await;
+    }
+}
+
+/// Final block among `in_blocks`, the blocks a final operation was included in.
+/// With several of them (e.g. forks), each one is fetched to find the final one.
+async fn final_block(client: &Client, in_blocks: &[BlockId]) -> Option<BlockId> {
+    if let [block_id] = in_blocks {
+        return Some(*block_id);
+    }
+    for block_id in in_blocks {
+        match client.public.get_block(*block_id).await {
+            Ok(block) if block.content.is_some_and(|content| content.is_final) => return Some(*block_id),
+            Ok(_) => {}
+            Err(e) => warn!(%block_id, "unable to fetch the block: {}", e),
+        }
+    }
+    None
+}
+
+/// Node calls `send_operation` depends on, extracted so it can run against a mock.
+#[async_trait]
+pub trait NodeApi {
+    async fn get_status(&self) -> RpcResult<NodeStatus>;
+
+    async fn send_operations(&self, operations: Vec<Operation>) -> RpcResult<Vec<OperationId>>;
+}
+
+#[async_trait]
+impl NodeApi for RpcClient {
+    async fn get_status(&self) -> RpcResult<NodeStatus> {
+        RpcClient::get_status(self).await
+    }
+
+    async fn send_operations(&self, operations: Vec<Operation>) -> RpcResult<Vec<OperationId>> {
+        RpcClient::send_operations(self, operations).await
+    }
+}
+
+/// Connections to the public and private APIs of the nodes.
+pub struct Client {
+    pub public: RpcClient,
+    pub private: RpcClient,
+}
 
 impl Client {
-    pub(crate) async fn new(ip: IpAddr, port: u16) -> Client {
-        let public_socket_addr = SocketAddr::new(ip, port);
-        let public_url = format!("http://{}", public_socket_addr);
-        Client(RpcClient::from_url(&public_url).await)
+    /// Clients of the APIs of the nodes, the first of `hosts` being used until it fails.
+    /// Each host is either a full `http(s)://` URL, used as is for both APIs, or an IP combined
+    /// with `public_port` and `private_port`, reached over HTTPS when `tls` is set.
+    pub async fn new(
+        hosts: &[String],
+        public_port: u16,
+        private_port: u16,
+        tls: bool,
+        proxy: Option<&str>,
+        max_attempts: u32,
+        timeout: Duration,
+    ) -> Result<Client> {
+        let mut public_urls = Vec::with_capacity(hosts.len());
+        let mut private_urls = Vec::with_capacity(hosts.len());
+        for host in hosts {
+            if host.contains("://") {
+                if !host.starts_with("http://") && !host.starts_with("https://") {
+                    return Err(RebuyError::Config(format!(
+                        "unsupported URL '{}': expected an http:// or https:// scheme",
+                        host
+                    )));
+                }
+                public_urls.push(host.to_string());
+                private_urls.push(host.to_string());
+            } else {
+                let ip: IpAddr = host
+                    .parse()
+                    .map_err(|_| RebuyError::Config(format!("invalid host '{}': expected an IP address or a URL", host)))?;
+                let scheme = if tls { "https" } else { "http" };
+                public_urls.push(format!("{}://{}", scheme, SocketAddr::new(ip, public_port)));
+                private_urls.push(format!("{}://{}", scheme, SocketAddr::new(ip, private_port)));
+            }
+        }
+        Ok(Client {
+            public: RpcClient::from_urls(&public_urls, proxy, max_attempts, timeout).await?,
+            private: RpcClient::from_urls(&private_urls, proxy, max_attempts, timeout).await?,
+        })
     }
 }
 
-pub struct RpcClient(TypedClient);
+/// Connection to one API of a node.
+enum Transport {
+    /// HTTP transport of `jsonrpc_core_client`
+    Direct(TypedClient),
+    /// JSON-RPC requests posted with `reqwest`, whose connector goes through a proxy. The
+    /// `jsonrpc_core_client` transport builds its own connector and cannot be given one
+    Proxied { http: reqwest::Client, url: String },
+}
 
-/// This is required by `jsonrpc_core_client::transports::http::connect`
-impl From<RpcChannel> for RpcClient {
-    fn from(channel: RpcChannel) -> Self {
-        RpcClient(channel.into())
+impl Transport {
+    /// Call `method` with `args`, deserializing its result as `returns`.
+    async fn call<R: DeserializeOwned + 'static>(&self, method: &str, returns: &str, args: serde_json::Value) -> RpcResult<R> {
+        let (http, url) = match self {
+            Transport::Direct(client) => return client.call_method(method, returns, args).await,
+            Transport::Proxied { http, url } => (http, url),
+        };
+        let request = serde_json::json!({ "jsonrpc": "2.0", "id": 0, "method": method, "params": args });
+        let mut response: serde_json::Value = http
+            .post(url)
+            .json(&request)
+            .send()
+            .await
+            .map_err(|e| RpcError::Other(Box::new(e)))?
+            .json()
+            .await
+            .map_err(|e| RpcError::Other(Box::new(e)))?;
+        let parse_error = |e: serde_json::Error| RpcError::ParseError(returns.to_string(), Box::new(e));
+        if let Some(error) = response.get_mut("error") {
+            return Err(RpcError::JsonRpcError(serde_json::from_value(error.take()).map_err(parse_error)?));
+        }
+        let result = response.get_mut("result").map_or(serde_json::Value::Null, serde_json::Value::take);
+        serde_json::from_value(result).map_err(parse_error)
     }
 }
 
+/// Clients of one API on each node, the index of the one currently used, and the time
+/// each call may take before failing with `RpcError::Timeout`.
+pub struct RpcClient {
+    nodes: Vec<(String, Transport)>,
+    active: AtomicUsize,
+    max_attempts: u32,
+    timeout: Duration,
+}
+
 /// Typed wrapper to API calls based on the method given by `jsonrpc_core_client`:
 ///
 /// ```rust
@@ -98,10 +648,112 @@ impl From<RpcChannel> for RpcClient {
 /// ```
 impl RpcClient {
     /// Default constructor
-    pub(crate) async fn from_url(url: &str) -> RpcClient {
-        match http::connect::<RpcClient>(url).await {
-            Ok(client) => client,
-            Err(_) => panic!("Unable to connect to Node."),
+    /// Only parses the URL, see `wait_ready` to wait for the node to answer.
+    /// Each call then fails if the node does not answer within `timeout`.
+    pub async fn from_url(url: &str, max_attempts: u32, timeout: Duration) -> Result<RpcClient> {
+        RpcClient::from_urls(&[url.to_string()], None, max_attempts, timeout).await
+    }
+
+    /// Clients of several nodes, calls failing over from one to the next when it is unreachable.
+    /// Fails at once on an invalid URL, the nodes themselves are not contacted.
+    /// With a `proxy` URL (`http://`, `https://` or `socks5://`), every request goes through it.
+    pub async fn from_urls(urls: &[String], proxy: Option<&str>, max_attempts: u32, timeout: Duration) -> Result<RpcClient> {
+        if urls.is_empty() {
+            return Err(RebuyError::Config("no node to connect to".to_string()));
+        }
+        let http = match proxy {
+            Some(proxy) => Some(
+                reqwest::Proxy::all(proxy)
+                    .and_then(|proxy| reqwest::Client::builder().proxy(proxy).build())
+                    .map_err(|e| RebuyError::Config(format!("invalid proxy '{}': {}", proxy, e)))?,
+            ),
+            None => None,
+        };
+        let mut nodes = Vec::with_capacity(urls.len());
+        for url in urls {
+            let transport = match &http {
+                Some(http) => Transport::Proxied {
+                    http: http.clone(),
+                    url: url.clone(),
+                },
+                // only parses the URL, nothing is sent until the first call
+                None => Transport::Direct(
+                    http::connect::<TypedClient>(url)
+                        .await
+                        .map_err(|e| RebuyError::Config(format!("invalid node URL '{}': {}", url, e)))?,
+                ),
+            };
+            nodes.push((url.clone(), transport));
+        }
+        Ok(RpcClient {
+            nodes,
+            active: AtomicUsize::new(0),
+            max_attempts,
+            timeout,
+        })
+    }
+
+    /// Wait for a node to answer a status call, e.g. while it restarts, retrying with an
+    /// exponential backoff (1s, 2s, 4s... capped at 60s) up to `max_attempts` times.
+    pub async fn wait_ready(&self) -> Result<()> {
+        match with_backoff("reach the node", self.max_attempts, || self.get_status()).await {
+            Ok(_) => Ok(()),
+            Err(e) => Err(RebuyError::Connection(format!("unable to reach the node: {}", e))),
+        }
+    }
+
+    /// Attempts made by `with_backoff` calls through this client, the `--connect-attempts`.
+    pub fn max_attempts(&self) -> u32 {
+        self.max_attempts
+    }
+
+    /// URL of the node the calls are currently sent to.
+    pub fn active_url(&self) -> &str {
+        &self.nodes[self.active.load(Ordering::Relaxed)].0
+    }
+
+    /// Call `method` on the active node, failing with `RpcError::Timeout` if it does not answer in time.
+    /// When it cannot be reached, the call is sent to the next node, which becomes the active one.
+    async fn call<T: Serialize, R: DeserializeOwned + 'static>(
+        &self,
+        method: &str,
+        returns: &str,
+        args: T,
+    ) -> RpcResult<R> {
+        // serialized once to be sent again to the next node
+        let args = serde_json::to_value(args).map_err(|e| RpcError::Client(e.to_string()))?;
+        let mut attempts = 0;
+        loop {
+            let index = self.active.load(Ordering::Relaxed);
+            let (url, client) = &self.nodes[index];
+            let start = Instant::now();
+            let result = match tokio::time::timeout(self.timeout, client.call(method, returns, args.clone())).await {
+                Ok(result) => result,
+                Err(_) => Err(RpcError::Timeout),
+            };
+            debug!(
+                target: RPC_TIMING_TARGET,
+                method,
+                node = %url,
+                elapsed_ms = start.elapsed().as_millis() as u64,
+                ok = result.is_ok(),
+                "rpc call"
+            );
+            attempts += 1;
+            match result {
+                Err(e) if is_transient(&e) && attempts < self.nodes.len() => {
+                    let next = (index + 1) % self.nodes.len();
+                    // concurrent calls failing on the same node only move to the next one once
+                    if self
+                        .active
+                        .compare_exchange(index, next, Ordering::Relaxed, Ordering::Relaxed)
+                        .is_ok()
+                    {
+                        warn!(from = %url, to = %self.nodes[next].0, method, "node unreachable, failing over: {}", e);
+                    }
+                }
+                result => return result,
+            }
         }
     }
 
@@ -110,54 +762,46 @@ impl RpcClient {
     /////////////////
 
     /// Gracefully stop the node.
-    pub(crate) async fn stop_node(&self) -> RpcResult<()> {
-        self.0.call_method("stop_node", "()", ()).await
+    pub async fn stop_node(&self) -> RpcResult<()> {
+        self.call("stop_node", "()", ()).await
     }
 
     /// Sign message with node's key.
     /// Returns the public key that signed the message and the signature.
-    pub(crate) async fn node_sign_message(&self, message: Vec<u8>) -> RpcResult<PubkeySig> {
-        self.0
-            .call_method("node_sign_message", "PubkeySig", vec![message])
-            .await
+    pub async fn node_sign_message(&self, message: Vec<u8>) -> RpcResult<PubkeySig> {
+        self.call("node_sign_message", "PubkeySig", vec![message]).await
     }
 
     /// Add a vec of new private keys for the node to use to stake.
     /// No confirmation to expect.
-    pub(crate) async fn add_staking_private_keys(
+    pub async fn add_staking_private_keys(
         &self,
         private_keys: Vec<PrivateKey>,
     ) -> RpcResult<()> {
-        self.0
-            .call_method("add_staking_private_keys", "()", vec![private_keys])
-            .await
+        self.call("add_staking_private_keys", "()", vec![private_keys]).await
     }
 
     /// Remove a vec of addresses used to stake.
     /// No confirmation to expect.
-    pub(crate) async fn remove_staking_addresses(&self, addresses: Vec<Address>) -> RpcResult<()> {
-        self.0
-            .call_method("remove_staking_addresses", "()", vec![addresses])
-            .await
+    pub async fn remove_staking_addresses(&self, addresses: Vec<Address>) -> RpcResult<()> {
+        self.call("remove_staking_addresses", "()", vec![addresses]).await
     }
 
     /// Return hashset of staking addresses.
-    pub(crate) async fn get_staking_addresses(&self) -> RpcResult<Set<Address>> {
-        self.0
-            .call_method("get_staking_addresses", "Set<Address>", ())
-            .await
+    pub async fn get_staking_addresses(&self) -> RpcResult<Set<Address>> {
+        self.call("get_staking_addresses", "Set<Address>", ()).await
     }
 
     /// Bans given node id
     /// No confirmation to expect.
-    pub(crate) async fn ban(&self, ips: Vec<IpAddr>) -> RpcResult<()> {
-        self.0.call_method("ban", "()", vec![ips]).await
+    pub async fn ban(&self, ips: Vec<IpAddr>) -> RpcResult<()> {
+        self.call("ban", "()", vec![ips]).await
     }
 
     /// Unbans given ip addr
     /// No confirmation to expect.
-    pub(crate) async fn unban(&self, ips: Vec<IpAddr>) -> RpcResult<()> {
-        self.0.call_method("unban", "()", vec![ips]).await
+    pub async fn unban(&self, ips: Vec<IpAddr>) -> RpcResult<()> {
+        self.call("unban", "()", vec![ips]).await
     }
 
     ////////////////
@@ -167,82 +811,99 @@ impl RpcClient {
     // Explorer (aggregated stats)
 
     /// summary of the current state: time, last final blocks (hash, thread, slot, timestamp), clique count, connected nodes count
-    pub(crate) async fn get_status(&self) -> RpcResult<NodeStatus> {
-        self.0.call_method("get_status", "NodeStatus", ()).await
+    pub async fn get_status(&self) -> RpcResult<NodeStatus> {
+        self.call("get_status", "NodeStatus", ()).await
     }
 
-    pub(crate) async fn _get_cliques(&self) -> RpcResult<Vec<Clique>> {
-        self.0.call_method("get_cliques", "Vec<Clique>", ()).await
+    pub async fn _get_cliques(&self) -> RpcResult<Vec<Clique>> {
+        self.call("get_cliques", "Vec<Clique>", ()).await
     }
 
     // Debug (specific information)
 
     /// Returns the active stakers and their roll counts for the current cycle.
-    pub(crate) async fn _get_stakers(&self) -> RpcResult<Map<Address, u64>> {
-        self.0
-            .call_method("get_stakers", "Map<Address, u64>", ())
-            .await
+    pub async fn get_stakers(&self) -> RpcResult<Map<Address, u64>> {
+        self.call("get_stakers", "Map<Address, u64>", ()).await
     }
 
     /// Returns operations information associated to a given list of operations' IDs.
-    pub(crate) async fn get_operations(
+    pub async fn get_operations(
         &self,
         operation_ids: Vec<OperationId>,
     ) -> RpcResult<Vec<OperationInfo>> {
-        self.0
-            .call_method("get_operations", "Vec<OperationInfo>", vec![operation_ids])
-            .await
+        self.call("get_operations", "Vec<OperationInfo>", vec![operation_ids]).await
     }
 
-    pub(crate) async fn get_endorsements(
+    pub async fn get_endorsements(
         &self,
         endorsement_ids: Vec<EndorsementId>,
     ) -> RpcResult<Vec<EndorsementInfo>> {
-        self.0
-            .call_method(
-                "get_endorsements",
-                "Vec<EndorsementInfo>",
-                vec![endorsement_ids],
-            )
-            .await
+        self.call("get_endorsements", "Vec<EndorsementInfo>", vec![endorsement_ids]).await
     }
 
     /// Get information on a block given its BlockId
-    pub(crate) async fn get_block(&self, block_id: BlockId) -> RpcResult<BlockInfo> {
-        self.0
-            .call_method("get_block", "BlockInfo", vec![block_id])
-            .await
+    pub async fn get_block(&self, block_id: BlockId) -> RpcResult<BlockInfo> {
+        self.call("get_block", "BlockInfo", vec![block_id]).await
     }
 
     /// Get the block graph within the specified time interval.
     /// Optional parameters: from <time_start> (included) and to <time_end> (excluded) millisecond timestamp
-    pub(crate) async fn _get_graph_interval(
+    pub async fn get_graph_interval(
         &self,
         time_interval: TimeInterval,
     ) -> RpcResult<Vec<BlockSummary>> {
-        self.0
-            .call_method("get_graph_interval", "Vec<BlockSummary>", time_interval)
-            .await
+        self.call("get_graph_interval", "Vec<BlockSummary>", vec![time_interval]).await
     }
 
-    pub(crate) async fn get_addresses(
+    pub async fn get_addresses(
         &self,
         addresses: Vec<Address>,
     ) -> RpcResult<Vec<AddressInfo>> {
-        self.0
-            .call_method("get_addresses", "Vec<AddressInfo>", vec![addresses])
-            .await
+        self.call("get_addresses", "Vec<AddressInfo>", vec![addresses]).await
+    }
+
+    /// Returns the smart contract output events matching the given filter.
+    pub async fn get_filtered_sc_output_event(
+        &self,
+        filter: EventFilter,
+    ) -> RpcResult<Vec<SCOutputEvent>> {
+        self.call("get_filtered_sc_output_event", "Vec<SCOutputEvent>", vec![filter]).await
     }
 
     // User (interaction with the node)
 
     /// Adds operations to pool. Returns operations that were ok and sent to pool.
-    pub(crate) async fn send_operations(
+    pub async fn send_operations(
         &self,
         operations: Vec<Operation>,
     ) -> RpcResult<Vec<OperationId>> {
-        self.0
-            .call_method("send_operations", "Vec<OperationId>", vec![operations])
-            .await
+        self.call("send_operations", "Vec<OperationId>", vec![operations]).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expire_period_thread_below_address_thread() {
+        assert_eq!(expire_period(Some(Slot::new(10, 2)), 5, 10), 20);
+    }
+
+    #[test]
+    fn expire_period_thread_equal_to_address_thread() {
+        assert_eq!(expire_period(Some(Slot::new(10, 5)), 5, 10), 21);
+    }
+
+    #[test]
+    fn expire_period_thread_above_address_thread() {
+        assert_eq!(expire_period(Some(Slot::new(10, 7)), 5, 10), 21);
+    }
+
+    #[test]
+    fn expire_period_before_genesis_counts_from_first_slot() {
+        // Slot::new(0, 0): thread 0 is passed for an address of thread 0 only
+        assert_eq!(expire_period(None, 0, 10), 11);
+        assert_eq!(expire_period(None, 3, 10), 10);
     }
 }
@@ -0,0 +1,176 @@
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use massa_models::prehash::Map;
+use massa_models::{Address, Amount, OperationId, Slot};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::error::{RebuyError, Result};
+use crate::rpc;
+
+/// Roll buy sent for an address, remembered to avoid buying twice before the node reflects it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LastBuy {
+    pub address: Address,
+    /// Milliseconds since the unix epoch when the buy was sent
+    pub timestamp: u64,
+    /// Latest slot reported by the node when the buy was sent
+    pub slot: Option<Slot>,
+    /// Candidate rolls of the address when the buy was decided
+    pub candidate_rolls: u64,
+    pub operation_ids: Vec<OperationId>,
+}
+
+/// Amount spent on a roll buy, rolls and fee included, counted against `--max-daily-spend`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Spend {
+    /// Milliseconds since the unix epoch when the buy was sent
+    pub timestamp: u64,
+    pub amount: Amount,
+}
+
+/// Content of the `--state-file`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct StateFile {
+    last_buys: Vec<LastBuy>,
+    #[serde(default)]
+    spends: Vec<Spend>,
+}
+
+/// What the rebuy check remembers between two iterations, and across restarts.
+#[derive(Default)]
+pub struct RebuyState {
+    last_buys: Map<Address, LastBuy>,
+    spends: Vec<Spend>,
+}
+
+impl RebuyState {
+    /// Read the state file at `path`, starting empty if it does not exist yet.
+    pub fn load(path: &Path) -> Result<RebuyState> {
+        if !path.exists() {
+            return Ok(RebuyState::default());
+        }
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| RebuyError::State(format!("unable to read state file {}: {}", path.display(), e)))?;
+        let file: StateFile = serde_json::from_str(&content)
+            .map_err(|e| RebuyError::State(format!("invalid state file {}: {}", path.display(), e)))?;
+        Ok(RebuyState {
+            last_buys: file
+                .last_buys
+                .into_iter()
+                .map(|last_buy| (last_buy.address, last_buy))
+                .collect(),
+            spends: file.spends,
+        })
+    }
+
+    /// Write the state to `path`, through a temporary file renamed over it so a crash
+    /// mid-write leaves the previous state rather than a truncated one.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let file = StateFile {
+            last_buys: self.last_buys.values().cloned().collect(),
+            spends: self.spends.clone(),
+        };
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(".tmp");
+        let temp = PathBuf::from(temp);
+        std::fs::write(&temp, serde_json::to_string_pretty(&file)?)
+            .and_then(|()| std::fs::rename(&temp, path))
+            .map_err(|e| RebuyError::State(format!("unable to write state file {}: {}", path.display(), e)))
+    }
+
+    /// Remember a buy just sent for `address`.
+    pub fn record_buy(
+        &mut self,
+        address: Address,
+        slot: Option<Slot>,
+        candidate_rolls: u64,
+        operation_ids: Vec<OperationId>,
+    ) {
+        self.last_buys.insert(
+            address,
+            LastBuy {
+                address,
+                timestamp: now(),
+                slot,
+                candidate_rolls,
+                operation_ids,
+            },
+        );
+    }
+
+    /// Remember `amount` was just spent on a roll buy.
+    pub fn record_spend(&mut self, amount: Amount) {
+        self.spends.push(Spend {
+            timestamp: now(),
+            amount,
+        });
+    }
+
+    /// Amount spent on roll buys during the last `window`, forgetting older spends.
+    pub fn spent_within(&mut self, window: Duration) -> Amount {
+        let start = now().saturating_sub(window.as_millis() as u64);
+        self.spends.retain(|spend| spend.timestamp >= start);
+        Amount::from_raw(
+            self.spends
+                .iter()
+                .fold(0u64, |total, spend| total.saturating_add(spend.amount.to_raw())),
+        )
+    }
+
+    /// Whether a buy was sent for `address` less than `cooldown` ago and the node still reports
+    /// the same `candidate_rolls` as back then.
+    pub fn is_cooling_down(&mut self, address: Address, candidate_rolls: u64, cooldown: Duration) -> bool {
+        match self.last_buys.get(&address) {
+            Some(last_buy)
+                if last_buy.candidate_rolls == candidate_rolls
+                    && now().saturating_sub(last_buy.timestamp) < cooldown.as_millis() as u64 =>
+            {
+                true
+            }
+            Some(_) => {
+                self.last_buys.remove(&address);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Check with the node whether the buys remembered from a previous run landed.
+    /// Buys whose operations the node does not know anymore are forgotten so they can be retried.
+    pub async fn reconcile(&mut self, client: &rpc::Client) {
+        let mut lost = Vec::new();
+        for last_buy in self.last_buys.values() {
+            if last_buy.operation_ids.is_empty() {
+                continue;
+            }
+            let operations = match client.public.get_operations(last_buy.operation_ids.clone()).await {
+                Ok(operations) => operations,
+                Err(e) => {
+                    warn!(address = %last_buy.address, "unable to check the previous roll buy: {}", e);
+                    continue;
+                }
+            };
+            if operations.iter().any(|operation| operation.is_final || !operation.in_blocks.is_empty()) {
+                info!(address = %last_buy.address, "previous roll buy landed");
+            } else if operations.iter().any(|operation| operation.in_pool) {
+                info!(address = %last_buy.address, "previous roll buy still pending in the pool");
+            } else {
+                warn!(address = %last_buy.address, "previous roll buy unknown to the node, forgetting it");
+                lost.push(last_buy.address);
+            }
+        }
+        for address in lost {
+            self.last_buys.remove(&address);
+        }
+    }
+}
+
+/// Milliseconds since the unix epoch.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or_default()
+}
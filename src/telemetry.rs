@@ -0,0 +1,54 @@
+//! Prometheus metrics of the rebuy loop.
+//! Recording is a no-op unless the crate is built with the `metrics` feature.
+
+use std::net::SocketAddr;
+
+use massa_models::{Address, Amount};
+
+use crate::error::{RebuyError, Result};
+
+/// Start the HTTP server exposing the metrics on `addr`.
+#[cfg(feature = "metrics")]
+pub fn install(addr: SocketAddr) -> Result<()> {
+    metrics_exporter_prometheus::PrometheusBuilder::new()
+        .with_http_listener(addr)
+        .install()
+        .map_err(|e| RebuyError::Config(format!("unable to serve metrics on {}: {}", addr, e)))
+}
+
+#[cfg(not(feature = "metrics"))]
+pub fn install(_addr: SocketAddr) -> Result<()> {
+    Err(RebuyError::Config(
+        "--metrics-addr requires building with the `metrics` feature".to_string(),
+    ))
+}
+
+/// A roll buy is about to be sent.
+pub fn rebuy_attempt() {
+    #[cfg(feature = "metrics")]
+    metrics::increment_counter!("massa_rebuy_attempts_total");
+}
+
+/// The node accepted a sent operation.
+pub fn operation_sent() {
+    #[cfg(feature = "metrics")]
+    metrics::increment_counter!("massa_rebuy_operations_sent_total");
+}
+
+/// A call to the node failed.
+pub fn rpc_error() {
+    #[cfg(feature = "metrics")]
+    metrics::increment_counter!("massa_rebuy_rpc_errors_total");
+}
+
+/// Latest rolls and balance (in nanomassa) observed for `address`.
+pub fn address_state(address: Address, candidate_rolls: u64, balance: Amount) {
+    #[cfg(feature = "metrics")]
+    {
+        let address = address.to_string();
+        metrics::gauge!("massa_rebuy_candidate_rolls", candidate_rolls as f64, "address" => address.clone());
+        metrics::gauge!("massa_rebuy_balance_nanomassa", balance.to_raw() as f64, "address" => address);
+    }
+    #[cfg(not(feature = "metrics"))]
+    let _ = (address, candidate_rolls, balance);
+}
@@ -0,0 +1,101 @@
+//! Several wallet files used as one, each address staying with the wallet holding its key.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use massa_models::Address;
+use massa_signature::{PrivateKey, PublicKey};
+use massa_wallet::Wallet;
+
+use crate::error::{RebuyError, Result};
+
+/// Wallets loaded from several files, with the wallet owning each address.
+pub struct Wallets {
+    paths: Vec<PathBuf>,
+    wallets: Vec<Wallet>,
+    owners: HashMap<Address, usize>,
+}
+
+impl Wallets {
+    /// Load the wallet files at `paths`.
+    /// Fails if one cannot be loaded or if two of them hold the same address.
+    pub fn load(paths: &[PathBuf]) -> Result<Wallets> {
+        let mut wallets = Vec::with_capacity(paths.len());
+        let mut owners = HashMap::new();
+        for (index, path) in paths.iter().enumerate() {
+            let wallet = load_wallet(path)?;
+            for address in wallet.get_full_wallet().keys() {
+                if let Some(owner) = owners.insert(*address, index) {
+                    return Err(RebuyError::WalletLoad(format!(
+                        "address {} is in both {} and {}",
+                        address,
+                        paths[owner].display(),
+                        path.display()
+                    )));
+                }
+            }
+            wallets.push(wallet);
+        }
+        Ok(Wallets {
+            paths: paths.to_vec(),
+            wallets,
+            owners,
+        })
+    }
+
+    /// Load the wallet files again, e.g. to pick up keys added since.
+    pub fn reload(&self) -> Result<Wallets> {
+        Wallets::load(&self.paths)
+    }
+
+    /// Wallet holding the key of `address`.
+    pub fn get(&self, address: Address) -> Option<&Wallet> {
+        self.owners.get(&address).map(|index| &self.wallets[*index])
+    }
+
+    /// Addresses of every wallet.
+    pub fn addresses(&self) -> Vec<Address> {
+        self.wallets
+            .iter()
+            .flat_map(|wallet| wallet.get_full_wallet().keys().copied())
+            .collect()
+    }
+
+    pub fn contains(&self, address: &Address) -> bool {
+        self.owners.contains_key(address)
+    }
+
+    pub fn find_associated_public_key(&self, address: Address) -> Option<&PublicKey> {
+        self.get(address)?.find_associated_public_key(address)
+    }
+
+    pub fn private_key(&self, address: Address) -> Option<PrivateKey> {
+        self.get(address)?.get_full_wallet().get(&address).copied()
+    }
+
+    /// Check every address of `addresses` has its public key in a wallet, to fail before talking
+    /// to the node rather than when sending its operations.
+    pub fn check_addresses(&self, addresses: &[Address]) -> Result<()> {
+        let missing: Vec<String> = addresses
+            .iter()
+            .filter(|address| self.find_associated_public_key(**address).is_none())
+            .map(ToString::to_string)
+            .collect();
+        if missing.is_empty() {
+            return Ok(());
+        }
+        Err(RebuyError::Config(format!(
+            "no public key in the wallet for {}",
+            missing.join(", ")
+        )))
+    }
+}
+
+/// Load the wallet file at `path`.
+pub fn load_wallet(path: &Path) -> Result<Wallet> {
+    if !path.is_file() {
+        return Err(RebuyError::WalletLoad(format!("wallet file {} does not exist", path.display())));
+    }
+    Wallet::new(path.to_path_buf())
+        .map_err(|e| RebuyError::WalletLoad(format!("unable to load wallet {}: {}", path.display(), e)))
+}
@@ -0,0 +1,334 @@
+//! In-process JSON-RPC node the rebuy checks run against over HTTP, with a wallet to check.
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use massa_auto_rebuy::ids::parse_block_id;
+use massa_auto_rebuy::rpc::{Client, FeeStrategy};
+use massa_auto_rebuy::{RebuyClient, RebuyReport, Settings, Wallets};
+use massa_models::{Address, Amount, Operation, Slot};
+use massa_signature::{derive_public_key, generate_random_private_key};
+use massa_wallet::Wallet;
+use serde_json::{json, Value};
+
+/// Roll price of the mock network, in MAS.
+pub const ROLL_PRICE: &str = "100";
+
+const THREAD_COUNT: u8 = 32;
+
+/// Slot duration of the mock network, in milliseconds.
+const T0: u64 = 16_000;
+
+/// Node answering `get_status`, `get_addresses`, `get_graph_interval` and `send_operations`,
+/// recording the parameters of every call.
+pub struct MockNode {
+    url: String,
+    calls: Arc<Mutex<Vec<(String, Value)>>>,
+}
+
+impl MockNode {
+    /// Serve a synced node returning `addresses`, see `address_info`, to `get_addresses`.
+    pub fn start(addresses: Vec<Value>) -> MockNode {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let node = Arc::new(Answers {
+            // the network started an hour ago
+            genesis_timestamp: now_millis() - 3_600_000,
+            addresses,
+            calls: calls.clone(),
+        });
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let node = node.clone();
+                std::thread::spawn(move || serve(stream, &node));
+            }
+        });
+        MockNode { url, calls }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Parameters of each call of `method` received so far.
+    pub fn calls(&self, method: &str) -> Vec<Value> {
+        let calls = self.calls.lock().unwrap();
+        calls
+            .iter()
+            .filter(|(called, _)| called == method)
+            .map(|(_, params)| params.clone())
+            .collect()
+    }
+
+    /// Operations received by `send_operations`, over all its calls.
+    pub fn sent_operations(&self) -> Vec<Operation> {
+        self.calls("send_operations")
+            .into_iter()
+            .flat_map(|params| serde_json::from_value::<Vec<Operation>>(params[0].clone()).unwrap())
+            .collect()
+    }
+}
+
+/// Canned answers of the mock node.
+struct Answers {
+    genesis_timestamp: u64,
+    addresses: Vec<Value>,
+    calls: Arc<Mutex<Vec<(String, Value)>>>,
+}
+
+impl Answers {
+    /// Result of `method`, or the JSON-RPC error of an unknown one.
+    fn answer(&self, method: &str, params: &Value) -> Result<Value, Value> {
+        self.calls.lock().unwrap().push((method.to_string(), params.clone()));
+        match method {
+            "get_status" => Ok(self.status()),
+            "get_addresses" => Ok(Value::Array(self.addresses.clone())),
+            "get_graph_interval" => Ok(json!([self.final_block()])),
+            "send_operations" => {
+                let operations: Vec<Operation> = serde_json::from_value(params[0].clone()).map_err(|e| rpc_error(-32602, e))?;
+                let operation_ids = operations
+                    .iter()
+                    .map(|operation| operation.get_operation_id().unwrap())
+                    .collect::<Vec<_>>();
+                Ok(serde_json::to_value(operation_ids).unwrap())
+            }
+            _ => Err(rpc_error(-32601, format!("method {} not found", method))),
+        }
+    }
+
+    /// Slot of the current time.
+    fn current_slot(&self) -> Slot {
+        let elapsed = now_millis() - self.genesis_timestamp;
+        Slot::new(elapsed / T0, ((elapsed % T0) * THREAD_COUNT as u64 / T0) as u8)
+    }
+
+    fn status(&self) -> Value {
+        let slot = self.current_slot();
+        let node_key = derive_public_key(&generate_random_private_key());
+        json!({
+            "node_id": node_key,
+            "node_ip": null,
+            "version": "TEST.8.0",
+            "current_time": now_millis(),
+            "current_cycle": slot.period / 128,
+            "connected_nodes": {},
+            "last_slot": slot,
+            "next_slot": slot.get_next_slot(THREAD_COUNT).unwrap(),
+            "consensus_stats": {
+                "start_timespan": now_millis() - 60_000,
+                "end_timespan": now_millis(),
+                "final_block_count": 100,
+                "final_operation_count": 0,
+                "stale_block_count": 0,
+                "clique_count": 1
+            },
+            "pool_stats": { "operation_count": 0, "endorsement_count": 0 },
+            "network_stats": {
+                "in_connection_count": 0,
+                "out_connection_count": 0,
+                "known_peer_count": 0,
+                "banned_peer_count": 0,
+                "active_node_count": 0
+            },
+            "execution_stats": {
+                "time_window_start": now_millis() - 60_000,
+                "time_window_end": now_millis(),
+                "final_block_count": 100,
+                "final_executed_operations_count": 0,
+                "active_cursor": slot
+            },
+            "config": {
+                "genesis_timestamp": self.genesis_timestamp,
+                "end_timestamp": null,
+                "thread_count": THREAD_COUNT,
+                "t0": T0,
+                "delta_f0": 64,
+                "operation_validity_periods": 10,
+                "periods_per_cycle": 128,
+                "pos_lookback_cycles": 2,
+                "pos_lock_cycles": 1,
+                "block_reward": amount("0.3"),
+                "roll_price": amount(ROLL_PRICE),
+                "max_block_size": 500_000,
+                "max_operations_per_block": 5_000,
+                "endorsement_count": 9
+            }
+        })
+    }
+
+    /// Block of the current slot, already final.
+    fn final_block(&self) -> Value {
+        let block_id = parse_block_id("stVppBFPTfo5Eu9LRGQktavngb3JqgeiJtGr7h8cnkgndqMAg").unwrap();
+        let creator = Address::from_public_key(&derive_public_key(&generate_random_private_key()));
+        json!({
+            "id": block_id,
+            "is_final": true,
+            "is_stale": false,
+            "is_in_blockclique": true,
+            "slot": self.current_slot(),
+            "creator": creator,
+            "parents": []
+        })
+    }
+}
+
+/// Answer the JSON-RPC requests of a connection until the client closes it.
+fn serve(stream: TcpStream, node: &Answers) {
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut writer = stream;
+    loop {
+        // request line, then headers up to an empty line
+        let mut line = String::new();
+        if reader.read_line(&mut line).unwrap_or(0) == 0 {
+            return;
+        }
+        let mut content_length = 0;
+        loop {
+            line.clear();
+            if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                return;
+            }
+            let header = line.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    content_length = value.trim().parse().unwrap();
+                }
+            }
+        }
+        let mut body = vec![0; content_length];
+        if reader.read_exact(&mut body).is_err() {
+            return;
+        }
+        let request: Value = serde_json::from_slice(&body).unwrap();
+        let method = request["method"].as_str().unwrap_or_default();
+        let response = match node.answer(method, &request["params"]) {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": request["id"], "result": result }),
+            Err(error) => json!({ "jsonrpc": "2.0", "id": request["id"], "error": error }),
+        };
+        let response = serde_json::to_vec(&response).unwrap();
+        let header = format!(
+            "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\n\r\n",
+            response.len()
+        );
+        if writer.write_all(header.as_bytes()).and_then(|()| writer.write_all(&response)).is_err() {
+            return;
+        }
+    }
+}
+
+fn rpc_error(code: i64, message: impl ToString) -> Value {
+    json!({ "code": code, "message": message.to_string() })
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64
+}
+
+/// `value` MAS, as serialized by the node.
+pub fn amount(value: &str) -> Value {
+    serde_json::to_value(Amount::from_str(value).unwrap()).unwrap()
+}
+
+/// `get_addresses` entry of `address` with a final balance of `balance` MAS and `candidate_rolls` rolls.
+pub fn address_info(address: Address, balance: &str, candidate_rolls: u64) -> Value {
+    json!({
+        "address": address,
+        "thread": address.get_thread(THREAD_COUNT),
+        "ledger_info": {
+            "locked_balance": amount("0"),
+            "candidate_ledger_info": { "balance": amount(balance) },
+            "final_ledger_info": { "balance": amount(balance) }
+        },
+        "rolls": {
+            "active_rolls": candidate_rolls,
+            "final_rolls": candidate_rolls,
+            "candidate_rolls": candidate_rolls
+        },
+        "sce_ledger_info": { "balance": amount("0"), "module": null, "datastore": {} },
+        "block_draws": [],
+        "endorsement_draws": [],
+        "blocks_created": [],
+        "involved_in_endorsements": [],
+        "involved_in_operations": [],
+        "production_stats": []
+    })
+}
+
+/// Wallet file holding a single fresh key, in a directory of its own.
+pub struct TestWallet {
+    pub dir: PathBuf,
+    pub path: PathBuf,
+    pub address: Address,
+}
+
+impl TestWallet {
+    /// Create the wallet in a temporary directory named after `test`.
+    pub fn create(test: &str) -> TestWallet {
+        let dir = std::env::temp_dir().join(format!("massa-auto-rebuy-{}-{}", test, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("wallet.dat");
+        let private_key = generate_random_private_key();
+        let address = Address::from_public_key(&derive_public_key(&private_key));
+        let mut wallet = Wallet::new(path.clone()).unwrap();
+        wallet.add_private_keys(vec![private_key]).unwrap();
+        TestWallet { dir, path, address }
+    }
+}
+
+/// Settings of a plain rebuy: one roll for addresses without rolls, paying no fee, the state
+/// being kept in `dir`.
+pub fn settings(dir: &Path) -> Settings {
+    Settings {
+        min_balance: None,
+        roll_count: 1,
+        addresses: Vec::new(),
+        greedy: false,
+        compound: false,
+        min_rolls: None,
+        max_rolls: None,
+        reserve: Amount::from_raw(0),
+        fee: FeeStrategy::Fixed(Amount::from_raw(0)),
+        max_fee: None,
+        dry_run: false,
+        check_only: false,
+        dry_run_json: false,
+        wait: None,
+        notifier: None,
+        max_concurrency: 1,
+        send_retries: 0,
+        clock_compensation: false,
+        cooldown: Duration::from_secs(300),
+        state_file: dir.join("state.json"),
+        auto_stake: false,
+        ensure_staking: false,
+        buy_at_cycle_start: false,
+        only_staking: false,
+        simulated_balance: None,
+        max_daily_spend: None,
+        expired_retries: 0,
+        fee_bump: 0,
+        overrides: HashMap::new(),
+        refresh_config_every: 60,
+        expected_roll_price: None,
+    }
+}
+
+/// Run a single check of `wallet` against `node` over HTTP.
+pub async fn check(node: &MockNode, wallet: &TestWallet) -> RebuyReport {
+    let client = Client::new(&[node.url().to_string()], 0, 0, false, None, 1, Duration::from_secs(5))
+        .await
+        .unwrap();
+    let wallets = Wallets::load(&[wallet.path.clone()]).unwrap();
+    let rebuy = RebuyClient::new(client, wallets, settings(&wallet.dir));
+    rebuy.check_and_rebuy().await.unwrap()
+}
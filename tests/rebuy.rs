@@ -0,0 +1,75 @@
+//! Rebuy checks run end to end against an in-process node, over the same HTTP transport as a real one.
+
+mod common;
+
+use common::{address_info, check, MockNode, TestWallet, ROLL_PRICE};
+use massa_auto_rebuy::Decision;
+use massa_models::{Address, OperationType};
+
+#[tokio::test]
+async fn sends_roll_buy_when_balance_covers_a_roll() {
+    let wallet = TestWallet::create("covers-a-roll");
+    let node = MockNode::start(vec![address_info(wallet.address, "150", 0)]);
+
+    let report = check(&node, &wallet).await;
+
+    let operations = node.sent_operations();
+    assert_eq!(operations.len(), 1);
+    let operation = &operations[0];
+    assert!(matches!(operation.content.op, OperationType::RollBuy { roll_count: 1 }));
+    assert_eq!(Address::from_public_key(&operation.content.sender_public_key), wallet.address);
+    assert_eq!(report.buys.len(), 1);
+    assert_eq!(report.buys[0].operation_ids, vec![operation.get_operation_id().unwrap()]);
+    assert_eq!(report.decisions[0].decision, Decision::Bought);
+}
+
+#[tokio::test]
+async fn sends_nothing_when_balance_is_below_the_roll_price() {
+    let wallet = TestWallet::create("below-roll-price");
+    let node = MockNode::start(vec![address_info(wallet.address, "50", 0)]);
+
+    let report = check(&node, &wallet).await;
+
+    assert!(node.calls("send_operations").is_empty());
+    assert!(report.buys.is_empty());
+    assert_eq!(report.decisions[0].decision, Decision::SkippedInsufficient);
+}
+
+#[tokio::test]
+async fn sends_nothing_when_the_address_owns_rolls() {
+    let wallet = TestWallet::create("owns-rolls");
+    let node = MockNode::start(vec![address_info(wallet.address, "150", 1)]);
+
+    let report = check(&node, &wallet).await;
+
+    assert!(node.calls("send_operations").is_empty());
+    assert_eq!(report.decisions[0].decision, Decision::SkippedHasRolls);
+}
+
+#[tokio::test]
+async fn sends_nothing_when_the_node_returns_no_address() {
+    let wallet = TestWallet::create("no-address");
+    let node = MockNode::start(Vec::new());
+
+    let report = check(&node, &wallet).await;
+
+    assert_eq!(node.calls("get_addresses").len(), 1);
+    assert!(node.calls("send_operations").is_empty());
+    assert!(report.addresses.is_empty());
+    assert!(report.decisions.is_empty());
+    assert!(report.buys.is_empty());
+    assert!(report.failures.is_empty());
+}
+
+#[tokio::test]
+async fn buys_when_balance_equals_the_threshold() {
+    // the threshold defaults to the roll price, and no fee is paid
+    let wallet = TestWallet::create("equals-threshold");
+    let node = MockNode::start(vec![address_info(wallet.address, ROLL_PRICE, 0)]);
+
+    let report = check(&node, &wallet).await;
+
+    assert_eq!(node.sent_operations().len(), 1);
+    assert_eq!(report.decisions[0].decision, Decision::Bought);
+    assert_eq!(report.buys[0].roll_count, 1);
+}